@@ -0,0 +1,65 @@
+const MOD_ADLER: u32 = 65521; // the largest prime below 2^16
+const NMAX: usize = 5552; // the most bytes that can be summed before the sums may overflow a u32
+
+pub fn adler32(buf: &[u8]) -> u32 {
+	update(1, buf)
+}
+
+pub fn update(adler: u32, buf: &[u8]) -> u32 {
+	// continues the adler32 of some data with the bytes following it.
+	// the low half is 1 + the sum of the bytes, the high half the sum of the low half after every byte, both modulo MOD_ADLER.
+	let mut a = adler & 0xFFFF;
+	let mut b = adler >> 16;
+	for chunk in buf.chunks(NMAX) {
+		for byte in chunk {
+			a += *byte as u32;
+			b += a;
+		}
+		a %= MOD_ADLER;
+		b %= MOD_ADLER;
+	}
+	(b << 16) | a
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn update_bytewise(adler: u32, buf: &[u8]) -> u32 {
+		// reduces after every byte, as in RFC 1950
+		let mut a = adler & 0xFFFF;
+		let mut b = adler >> 16;
+		for byte in buf {
+			a = (a + *byte as u32) % MOD_ADLER;
+			b = (b + a) % MOD_ADLER;
+		}
+		(b << 16) | a
+	}
+
+	#[test]
+	fn known_values() {
+		assert_eq!(adler32(b""), 1);
+		assert_eq!(adler32(b"a"), 0x00620062);
+		assert_eq!(adler32(b"abc"), 0x024D0127);
+		assert_eq!(adler32(b"Wikipedia"), 0x11E60398);
+	}
+
+	#[test]
+	fn longer_than_nmax() {
+		// all 0xFF bytes make the sums grow fastest, so each batch of NMAX bytes is the most that fits in a u32
+		let ones = vec![0xFF; 4 * NMAX + 100];
+		let mut x: u32 = 1;
+		let mixed: Vec<u8> = (0..4 * NMAX + 100).map(|_| {
+			x = x.wrapping_mul(1103515245).wrapping_add(12345);
+			(x >> 16) as u8
+		}).collect();
+		for data in [&ones, &mixed] {
+			for len in [NMAX - 1, NMAX, NMAX + 1, 2 * NMAX, 3 * NMAX + 7, data.len()] {
+				assert_eq!(adler32(&data[..len]), update_bytewise(1, &data[..len]), "len {}", len);
+			}
+			// both sums at their largest before a batch is the case NMAX is chosen for, a u32 overflow panics here
+			let largest = ((MOD_ADLER - 1) << 16) | (MOD_ADLER - 1);
+			assert_eq!(update(largest, data), update_bytewise(largest, data));
+		}
+	}
+}
@@ -47,10 +47,359 @@ const CRC32_TAB: [u32; 256] = [
 ];
 
 pub fn crc32(buf: &[u8]) -> u32 {
-	let mut crc: u32;
-	crc = !0u32;
+	update(0, buf)
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Crc32 {
+	// the crc32 of data given in parts, as with Crc32::new(), update(a), update(b), finalize().
+	// also counts the bytes, so states of consecutive parts can be combined.
+	crc: u32,
+	amount: u64,
+}
+
+impl Crc32 {
+	pub fn new() -> Crc32 {
+		Crc32 { crc: 0, amount: 0 }
+	}
+
+	pub fn update(&mut self, buf: &[u8]) {
+		self.crc = update(self.crc, buf);
+		self.amount += buf.len() as u64;
+	}
+
+	pub fn combine(&mut self, other: &Crc32) {
+		// as if the data of other was given to self after its own
+		self.crc = combine(self.crc, other.crc, other.amount);
+		self.amount += other.amount;
+	}
+
+	pub fn finalize(&self) -> u32 {
+		// the crc32 of everything so far. more can still be added.
+		self.crc
+	}
+
+	pub fn amount(&self) -> u64 {
+		// how many bytes went in
+		self.amount
+	}
+}
+
+impl std::hash::Hasher for Crc32 {
+	fn write(&mut self, bytes: &[u8]) {
+		self.update(bytes);
+	}
+
+	fn finish(&self) -> u64 {
+		self.finalize() as u64
+	}
+}
+
+pub fn update(crc: u32, buf: &[u8]) -> u32 {
+	// continues the crc32 of some data with the bytes following it.
+	// uses the cpu's carry-less multiplication or crc instructions when it has them, slicing-by-8 otherwise.
+	update_accelerated(crc, buf).unwrap_or_else(|| update_slicing_by_8(crc, buf))
+}
+
+#[cfg(target_arch = "x86_64")]
+fn update_accelerated(crc: u32, buf: &[u8]) -> Option<u32> {
+	// the folding only pays off for a few blocks of 16 bytes
+	if buf.len() >= 128 && is_x86_feature_detected!("pclmulqdq") && is_x86_feature_detected!("sse4.1") {
+		Some(unsafe { pclmul::update(crc, buf) })
+	} else {
+		None
+	}
+}
+
+#[cfg(target_arch = "aarch64")]
+fn update_accelerated(crc: u32, buf: &[u8]) -> Option<u32> {
+	if std::arch::is_aarch64_feature_detected!("crc") {
+		Some(unsafe { armv8::update(crc, buf) })
+	} else {
+		None
+	}
+}
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+fn update_accelerated(_crc: u32, _buf: &[u8]) -> Option<u32> {
+	None
+}
+
+fn update_bytewise(crc: u32, buf: &[u8]) -> u32 {
+	let mut crc = !crc;
 	for b in buf {
 		crc = CRC32_TAB[((crc ^ *b as u32) & 0xFF) as usize] ^ (crc >> 8);
 	}
-	crc ^ !0u32
+	!crc
+}
+
+// SLICING_TABS[k][b] is the crc of byte b followed by k zero bytes, so 8 bytes are done with 8 independent lookups.
+static SLICING_TABS: [[u32; 256]; 8] = slicing_tabs();
+
+const fn slicing_tabs() -> [[u32; 256]; 8] {
+	let mut tabs = [[0; 256]; 8];
+	tabs[0] = CRC32_TAB;
+	let mut k = 1;
+	while k < 8 {
+		let mut b = 0;
+		while b < 256 {
+			let prev = tabs[k - 1][b];
+			tabs[k][b] = (prev >> 8) ^ CRC32_TAB[(prev & 0xFF) as usize];
+			b += 1;
+		}
+		k += 1;
+	}
+	tabs
+}
+
+fn update_slicing_by_8(crc: u32, buf: &[u8]) -> u32 {
+	let t = &SLICING_TABS;
+	let mut crc = !crc;
+	let mut chunks = buf.chunks_exact(8);
+	for chunk in &mut chunks {
+		let lo = crc ^ u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+		let hi = u32::from_le_bytes([chunk[4], chunk[5], chunk[6], chunk[7]]);
+		crc = t[7][(lo & 0xFF) as usize] ^ t[6][((lo >> 8) & 0xFF) as usize]
+			^ t[5][((lo >> 16) & 0xFF) as usize] ^ t[4][(lo >> 24) as usize]
+			^ t[3][(hi & 0xFF) as usize] ^ t[2][((hi >> 8) & 0xFF) as usize]
+			^ t[1][((hi >> 16) & 0xFF) as usize] ^ t[0][(hi >> 24) as usize];
+	}
+	update_bytewise(!crc, chunks.remainder())
+}
+
+#[cfg(target_arch = "x86_64")]
+mod pclmul {
+	// folds 64 bytes at a time with carry-less multiplication, then barrett reduces to 32 bits.
+	// from intel's "Fast CRC Computation for Generic Polynomials Using PCLMULQDQ Instruction", as done in linux and chromium.
+	use std::arch::x86_64::*;
+
+	// x^n mod P for the fold distances, bit reflected
+	const K1: i64 = 0x154442bd4; // x^(4*128+32)
+	const K2: i64 = 0x1c6e41596; // x^(4*128-32)
+	const K3: i64 = 0x1751997d0; // x^(128+32)
+	const K4: i64 = 0x0ccaa009e; // x^(128-32)
+	const K5: i64 = 0x163cd6124; // x^64
+	const P: i64 = 0x1db710641; // the polynomial
+	const MU: i64 = 0x1f7011641; // x^64 / P
+
+	#[target_feature(enable = "pclmulqdq", enable = "sse2", enable = "sse4.1")]
+	pub unsafe fn update(crc: u32, mut buf: &[u8]) -> u32 {
+		// buf must be at least 64 bytes long
+		let mut x3 = load(&mut buf);
+		let mut x2 = load(&mut buf);
+		let mut x1 = load(&mut buf);
+		let mut x0 = load(&mut buf);
+		x3 = _mm_xor_si128(x3, _mm_cvtsi32_si128(!crc as i32));
+
+		let k1k2 = _mm_set_epi64x(K2, K1);
+		while buf.len() >= 64 {
+			x3 = fold(x3, load(&mut buf), k1k2);
+			x2 = fold(x2, load(&mut buf), k1k2);
+			x1 = fold(x1, load(&mut buf), k1k2);
+			x0 = fold(x0, load(&mut buf), k1k2);
+		}
+
+		let k3k4 = _mm_set_epi64x(K4, K3);
+		let mut x = fold(x3, x2, k3k4);
+		x = fold(x, x1, k3k4);
+		x = fold(x, x0, k3k4);
+		while buf.len() >= 16 {
+			x = fold(x, load(&mut buf), k3k4);
+		}
+
+		// 128 to 64 bits
+		let low32 = _mm_set_epi32(0, 0, 0, !0);
+		let x = _mm_xor_si128(_mm_clmulepi64_si128(x, k3k4, 0x10), _mm_srli_si128(x, 8));
+		let x = _mm_xor_si128(_mm_clmulepi64_si128(_mm_and_si128(x, low32), _mm_set_epi64x(0, K5), 0x00), _mm_srli_si128(x, 4));
+
+		// barrett reduction to 32 bits
+		let pu = _mm_set_epi64x(MU, P);
+		let t1 = _mm_clmulepi64_si128(_mm_and_si128(x, low32), pu, 0x10);
+		let t2 = _mm_clmulepi64_si128(_mm_and_si128(t1, low32), pu, 0x00);
+		let crc = _mm_extract_epi32(_mm_xor_si128(x, t2), 1) as u32;
+
+		super::update_slicing_by_8(!crc, buf)
+	}
+
+	#[target_feature(enable = "pclmulqdq", enable = "sse2")]
+	unsafe fn fold(a: __m128i, b: __m128i, keys: __m128i) -> __m128i {
+		// a moved 128 bits forward (or 512, depending on keys), plus b
+		let lo = _mm_clmulepi64_si128(a, keys, 0x00);
+		let hi = _mm_clmulepi64_si128(a, keys, 0x11);
+		_mm_xor_si128(_mm_xor_si128(b, lo), hi)
+	}
+
+	#[target_feature(enable = "sse2")]
+	unsafe fn load(buf: &mut &[u8]) -> __m128i {
+		let (head, rest) = buf.split_at(16);
+		*buf = rest;
+		_mm_loadu_si128(head.as_ptr() as *const __m128i)
+	}
+}
+
+#[cfg(target_arch = "aarch64")]
+mod armv8 {
+	use std::arch::aarch64::{__crc32b, __crc32d};
+
+	#[target_feature(enable = "crc")]
+	pub unsafe fn update(crc: u32, buf: &[u8]) -> u32 {
+		let mut crc = !crc;
+		let mut chunks = buf.chunks_exact(8);
+		for chunk in &mut chunks {
+			let mut word = [0; 8];
+			word.copy_from_slice(chunk);
+			crc = __crc32d(crc, u64::from_le_bytes(word));
+		}
+		for b in chunks.remainder() {
+			crc = __crc32b(crc, *b);
+		}
+		!crc
+	}
+}
+
+pub fn combine(crc_a: u32, crc_b: u32, len_b: u64) -> u32 {
+	// the crc32 of some data a followed by b, given the crc32s of both and the length of b (zlib's crc32_combine).
+	// appending a zero bit to the data is a linear operation over GF(2) on the crc, so appending len_b zero bytes
+	// to a is done with powers of that operator gotten by repeated squaring. xoring with crc_b then adds b.
+	if len_b == 0 {
+		return crc_a;
+	}
+	let mut odd = [0u32; 32]; // the operator for one zero bit
+	odd[0] = 0xEDB88320; // the reversed polynomial
+	for (n, row) in odd.iter_mut().enumerate().skip(1) {
+		*row = 1 << (n - 1);
+	}
+	let mut even = [0u32; 32];
+	gf2_matrix_square(&mut even, &odd); // two zero bits
+	gf2_matrix_square(&mut odd, &even); // four zero bits
+
+	// the first squaring below gives one zero byte
+	let mut crc = crc_a;
+	let mut len = len_b;
+	loop {
+		gf2_matrix_square(&mut even, &odd);
+		if len & 1 != 0 {
+			crc = gf2_matrix_times(&even, crc);
+		}
+		len >>= 1;
+		if len == 0 {
+			break;
+		}
+		gf2_matrix_square(&mut odd, &even);
+		if len & 1 != 0 {
+			crc = gf2_matrix_times(&odd, crc);
+		}
+		len >>= 1;
+		if len == 0 {
+			break;
+		}
+	}
+	crc ^ crc_b
+}
+
+fn gf2_matrix_times(matrix: &[u32; 32], mut vector: u32) -> u32 {
+	let mut sum = 0;
+	let mut i = 0;
+	while vector != 0 {
+		if vector & 1 != 0 {
+			sum ^= matrix[i];
+		}
+		vector >>= 1;
+		i += 1;
+	}
+	sum
+}
+
+fn gf2_matrix_square(square: &mut [u32; 32], matrix: &[u32; 32]) {
+	for (row, &m) in square.iter_mut().zip(matrix) {
+		*row = gf2_matrix_times(matrix, m);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn test_data(len: usize) -> Vec<u8> {
+		// xorshift, so the data is the same every run
+		let mut x: u32 = 0x9E3779B9;
+		(0..len).map(|_| {
+			x ^= x << 13;
+			x ^= x >> 17;
+			x ^= x << 5;
+			x as u8
+		}).collect()
+	}
+
+	fn check_against_bytewise(update: impl Fn(u32, &[u8]) -> u32, min_len: usize) {
+		// every length up to a few folds of 64 bytes, at every alignment, from a zero and a nonzero crc
+		let data = test_data(1024 + 16);
+		for offset in 0..16 {
+			for len in min_len..=1024 {
+				let buf = &data[offset..offset + len];
+				for crc in [0, 0xDEADBEEF] {
+					assert_eq!(update(crc, buf), update_bytewise(crc, buf), "offset {} len {} crc {:08x}", offset, len, crc);
+				}
+			}
+		}
+	}
+
+	#[test]
+	fn known_values() {
+		assert_eq!(crc32(b""), 0);
+		assert_eq!(crc32(b"123456789"), 0xCBF43926);
+		assert_eq!(crc32(b"The quick brown fox jumps over the lazy dog"), 0x414FA339);
+	}
+
+	#[test]
+	fn slicing_by_8() {
+		check_against_bytewise(update_slicing_by_8, 0);
+	}
+
+	#[test]
+	fn dispatch() {
+		check_against_bytewise(update, 0);
+	}
+
+	#[cfg(target_arch = "x86_64")]
+	#[test]
+	fn pclmul() {
+		if is_x86_feature_detected!("pclmulqdq") && is_x86_feature_detected!("sse4.1") {
+			check_against_bytewise(|crc, buf| unsafe { pclmul::update(crc, buf) }, 64);
+		}
+	}
+
+	#[cfg(target_arch = "aarch64")]
+	#[test]
+	fn armv8() {
+		if std::arch::is_aarch64_feature_detected!("crc") {
+			check_against_bytewise(|crc, buf| unsafe { armv8::update(crc, buf) }, 0);
+		}
+	}
+
+	#[test]
+	fn combine_parts() {
+		let data = test_data(3000);
+		for split in [0, 1, 7, 64, 1000, 2999, 3000] {
+			let (a, b) = data.split_at(split);
+			assert_eq!(combine(crc32(a), crc32(b), b.len() as u64), crc32(&data), "split {}", split);
+
+			let mut state_a = Crc32::new();
+			state_a.update(a);
+			let mut state_b = Crc32::new();
+			state_b.update(b);
+			state_a.combine(&state_b);
+			assert_eq!(state_a.finalize(), crc32(&data));
+			assert_eq!(state_a.amount(), data.len() as u64);
+		}
+	}
+
+	#[test]
+	fn combine_long() {
+		// lengths past 32 bits: combining a, b and c either way round gives the same crc
+		let (a, b, c) = (0x12345678, 0x9ABCDEF0, 0x0F1E2D3C);
+		for (len_b, len_c) in [(1 << 32, 5), (3, (1 << 33) + 1), (u32::MAX as u64, u32::MAX as u64)] {
+			assert_eq!(combine(combine(a, b, len_b), c, len_c), combine(a, combine(b, c, len_c), len_b + len_c));
+		}
+	}
 }
@@ -1,33 +1,250 @@
-use std::io::Write;
+use std::io::{self, Write};
 
+use crate::crc32::Crc32;
+use crate::error::GuyzipError;
 use crate::huffman;
+use crate::options::{CompressionOptions, Strategy};
 mod lempel_ziv;
+mod greedy;
+mod match_finder;
 mod block_splitter;
 use block_splitter::Block;
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Token {
 	Literal(u8),
 	Repeat(u32, u32),
 }
 
-pub fn deflate<T: Write>(file: &[u8], out: &mut T) {
-	let tokens = lempel_ziv::lempel_ziv(file);
-	let blocks = block_splitter::block_split(&tokens);
+impl Token {
+	pub(crate) fn data_len(&self) -> usize {
+		// how many bytes of data the token stands for
+		match self {
+			Token::Literal(_) => 1,
+			Token::Repeat(len, _dist) => *len as usize,
+		}
+	}
+}
+
+#[derive(Clone, Copy)]
+pub(crate) struct PackedToken(u32);
+// a Token in 4 bytes instead of 12, for the token lists of whole chunks:
+// the length (the value for a literal) in the high half, the distance (0 for a literal) in the low one.
+
+impl PackedToken {
+	pub(crate) fn get(self) -> Token {
+		match self.0 & 0xFFFF {
+			0 => Token::Literal((self.0 >> 16) as u8),
+			dist => Token::Repeat(self.0 >> 16, dist),
+		}
+	}
+}
+
+impl From<Token> for PackedToken {
+	fn from(token: Token) -> PackedToken {
+		match token {
+			Token::Literal(value) => PackedToken((value as u32) << 16),
+			Token::Repeat(len, dist) => {
+				// only the parsers pack tokens, their repetitions always fit
+				debug_assert!((3..=258).contains(&len) && (1..=32768).contains(&dist));
+				PackedToken(len << 16 | dist)
+			}
+		}
+	}
+}
+
+const CHUNK_SIZE: usize = 1 << 20; // how much new input DeflateEncoder gathers before compressing it
+pub(crate) const MAX_STORED_BLOCK_LEN: usize = 65535;
+
+pub fn deflate<T: Write>(data: &[u8], dictionary_len: usize, is_last: bool, options: &CompressionOptions, out: &mut T) -> Result<(), GuyzipError> {
+	// compresses data[dictionary_len..], repetitions may refer back into the dictionary.
+	// unless is_last, ends with an empty stored block (a sync point), leaving out byte aligned for more blocks to follow.
+	let tokens = match options.level.strategy() {
+		Strategy::Store => return store(&data[dictionary_len..], is_last, out),
+		Strategy::Greedy(params) => greedy::greedy(data, dictionary_len, params),
+		Strategy::Lazy(params) => greedy::lazy(data, dictionary_len, params),
+		Strategy::Optimal if options.iterations > 0 => {
+			return deflate_iteratively(data, dictionary_len, is_last, options, out);
+		}
+		Strategy::Optimal => lempel_ziv::lempel_ziv(data, dictionary_len, &[], options.finder_params()),
+	};
+	write_blocks(&block_splitter::block_split(&tokens, &data[dictionary_len..]), is_last, out)
+}
+
+fn store<T: Write>(data: &[u8], is_last: bool, out: &mut T) -> Result<(), GuyzipError> {
+	// stored blocks end byte aligned anyway, no sync point needed
 	let mut writer = DeflateWriter::new(out);
+	writer.write_stored_blocks(is_last, data);
+	writer.finish()
+}
 
+fn deflate_iteratively<T: Write>(data: &[u8], dictionary_len: usize, is_last: bool, options: &CompressionOptions, out: &mut T) -> Result<(), GuyzipError> {
+	// zopfli style: parse again using the code lengths the blocks of the previous parse got, keep the smallest output.
+	let mut tokens = lempel_ziv::lempel_ziv(data, dictionary_len, &[], options.finder_params());
+	let mut best = vec![];
+	let blocks = block_splitter::block_split(&tokens, &data[dictionary_len..]);
+	write_blocks(&blocks, is_last, &mut best)?;
+	let mut costs = costs_of_blocks(&blocks, dictionary_len);
+
+	for _ in 0..options.iterations {
+		tokens = lempel_ziv::lempel_ziv(data, dictionary_len, &costs, options.finder_params());
+		let blocks = block_splitter::block_split(&tokens, &data[dictionary_len..]);
+		let mut candidate = vec![];
+		write_blocks(&blocks, is_last, &mut candidate)?;
+		if candidate.len() < best.len() {
+			best = candidate;
+		}
+		costs = costs_of_blocks(&blocks, dictionary_len);
+	}
+	out.write_all(&best)?;
+	Ok(())
+}
+
+fn costs_of_blocks(blocks: &[Block], start: usize) -> Vec<lempel_ziv::BlockCosts> {
+	let mut end = start;
+	blocks.iter().map(|block| {
+		end += block.tokens().map(|t| t.data_len()).sum::<usize>();
+		let (literal_code_lens, distance_code_lens) = block.code_lens();
+		lempel_ziv::BlockCosts::new(end, literal_code_lens, distance_code_lens)
+	}).collect()
+}
+
+fn write_blocks<T: Write>(blocks: &[Block], is_last: bool, out: &mut T) -> Result<(), GuyzipError> {
+	// unless is_last, ends with a sync point.
+	let mut writer = DeflateWriter::new(out);
 	for (i, block) in blocks.iter().enumerate() {
-		let is_last = i == blocks.len() - 1;
+		let is_last_block = is_last && i == blocks.len() - 1;
 		match block {
-			Block::FixedCodes { tokens } => {
-				writer.new_fixed_codes_block(is_last);
-				tokens.iter().for_each(|t| writer.write(t));
+			Block::Stored { data, .. } => {
+				writer.write_stored_blocks(is_last_block, data);
+			}
+			Block::FixedCodes { .. } => {
+				writer.new_fixed_codes_block(is_last_block);
+				for token in block.tokens() {
+					writer.write(&token);
+				}
 			}
-			Block::DynamicCodes { tokens, literal_code_lens, distance_code_lens } => {
-				writer.new_dynamic_codes_block(is_last, literal_code_lens, distance_code_lens);
-				tokens.iter().for_each(|t| writer.write(t));
+			Block::DynamicCodes { literal_code_lens, distance_code_lens, .. } => {
+				writer.new_dynamic_codes_block(is_last_block, literal_code_lens, distance_code_lens);
+				for token in block.tokens() {
+					writer.write(&token);
+				}
 			}
 		}
 	}
+	if is_last && blocks.is_empty() {
+		// nothing left to compress, but the stream still needs a final block
+		writer.new_fixed_codes_block(true);
+	}
+	if !is_last {
+		writer.sync_flush();
+	}
+	writer.finish()
+}
+
+pub struct DeflateEncoder<W: Write> {
+	// compresses data written to it in chunks of CHUNK_SIZE, keeping the end of the previous chunk as a dictionary.
+	// with more than one thread, that many chunks are gathered and compressed in parallel.
+	// each chunk is compressed the same way either way, so the output doesn't depend on the thread count.
+	out: W,
+	options: CompressionOptions,
+	buf: Vec<u8>, // the dictionary followed by input that wasn't compressed yet
+	dictionary_len: usize,
+	crc32: Crc32, // of the input compressed so far
+}
+
+impl<W: Write> DeflateEncoder<W> {
+	pub fn new(out: W) -> DeflateEncoder<W> {
+		DeflateEncoder::with_options(out, CompressionOptions::default())
+	}
+
+	pub fn with_options(out: W, options: CompressionOptions) -> DeflateEncoder<W> {
+		DeflateEncoder {
+			out,
+			options,
+			buf: Vec::with_capacity(lempel_ziv::MAX_REP_DIST + CHUNK_SIZE * options.threads.max(1)),
+			dictionary_len: 0,
+			crc32: Crc32::new(),
+		}
+	}
+
+	pub fn finish(self) -> Result<W, GuyzipError> {
+		self.finish_with_crc32().map(|(out, _crc32)| out)
+	}
+
+	pub(crate) fn finish_with_crc32(mut self) -> Result<(W, Crc32), GuyzipError> {
+		// also returns the crc32 and length of all the input, computed along with the compression
+		self.compress_buf(true)?;
+		Ok((self.out, self.crc32))
+	}
+
+	fn pending_limit(&self) -> usize {
+		// how much input is gathered before compressing
+		CHUNK_SIZE * self.options.threads.max(1)
+	}
+
+	fn compress_buf(&mut self, is_last: bool) -> Result<(), GuyzipError> {
+		let buf = &self.buf;
+		let options = &self.options;
+		let compress_chunk = |start: usize| {
+			// compresses buf[start..] up to CHUNK_SIZE bytes, with the MAX_REP_DIST bytes before it as the dictionary
+			let end = (start + CHUNK_SIZE).min(buf.len());
+			let dictionary_start = start.saturating_sub(lempel_ziv::MAX_REP_DIST);
+			let mut compressed = vec![];
+			deflate(&buf[dictionary_start..end], start - dictionary_start, is_last && end == buf.len(), options, &mut compressed)?;
+			let mut crc32 = Crc32::new();
+			crc32.update(&buf[start..end]);
+			Ok((compressed, crc32))
+		};
+
+		let mut chunk_starts: Vec<usize> = (self.dictionary_len..buf.len()).step_by(CHUNK_SIZE).collect();
+		if chunk_starts.is_empty() {
+			// nothing to compress, but the final block is still needed
+			chunk_starts.push(self.dictionary_len);
+		}
+		let chunks: Vec<Result<_, GuyzipError>> = if chunk_starts.len() == 1 {
+			chunk_starts.into_iter().map(compress_chunk).collect()
+		} else {
+			std::thread::scope(|scope| {
+				let workers: Vec<_> = chunk_starts.into_iter()
+					.map(|start| scope.spawn(move || compress_chunk(start)))
+					.collect();
+				// a panicking worker takes the calling thread down with it, as it would without threads
+				workers.into_iter().map(|worker| worker.join().unwrap_or_else(|e| std::panic::resume_unwind(e))).collect()
+			})
+		};
+		for chunk in chunks {
+			let (compressed, crc32) = chunk?;
+			self.out.write_all(&compressed)?;
+			self.crc32.combine(&crc32);
+		}
+
+		let new_dictionary_start = self.buf.len().saturating_sub(lempel_ziv::MAX_REP_DIST);
+		self.buf.drain(..new_dictionary_start);
+		self.dictionary_len = self.buf.len();
+		Ok(())
+	}
+}
+
+impl<W: Write> Write for DeflateEncoder<W> {
+	fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+		// only takes what fits in the chunks being gathered, so buf never grows beyond the dictionary and one chunk per thread.
+		let pending = self.buf.len() - self.dictionary_len;
+		let taken = data.len().min(self.pending_limit() - pending);
+		self.buf.extend_from_slice(&data[..taken]);
+		if pending + taken == self.pending_limit() {
+			self.compress_buf(false)?;
+		}
+		Ok(taken)
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		// compresses everything written so far and ends it at a sync point.
+		if self.buf.len() > self.dictionary_len {
+			self.compress_buf(false)?;
+		}
+		self.out.flush()
+	}
 }
 
 struct DeflateWriter<'a, T: Write> {
@@ -37,6 +254,7 @@ struct DeflateWriter<'a, T: Write> {
 	literal_tree: huffman::Tree,
 	distance_tree: huffman::Tree,
 	in_block: bool,
+	error: Option<io::Error>, // the first write that failed, nothing is written after it. finish returns it
 }
 
 impl<'a, T: Write> DeflateWriter<'a, T> {
@@ -48,6 +266,25 @@ impl<'a, T: Write> DeflateWriter<'a, T> {
 			literal_tree: vec![],
 			distance_tree: vec![],
 			in_block: false,
+			error: None,
+		}
+	}
+
+	fn finish(mut self) -> Result<(), GuyzipError> {
+		// ends the current block and pads the output to a whole byte
+		self.end_block();
+		self.pad_to_byte();
+		match self.error {
+			Some(e) => Err(e.into()),
+			None => Ok(()),
+		}
+	}
+
+	fn write_bytes(&mut self, bytes: &[u8]) {
+		if self.error.is_none() {
+			if let Err(e) = self.out.write_all(bytes) {
+				self.error = Some(e);
+			}
 		}
 	}
 
@@ -57,25 +294,26 @@ impl<'a, T: Write> DeflateWriter<'a, T> {
 		self.curr_bytes |= bits << self.curr_full_bits;
 		self.curr_full_bits += len;
 		while self.curr_full_bits >= 8 {
-			self.out.write_all(&[(self.curr_bytes & 0xFF) as u8]).unwrap();
+			self.write_bytes(&[(self.curr_bytes & 0xFF) as u8]);
 			self.curr_bytes >>= 8;
 			self.curr_full_bits -= 8;
 		}
 	}
 
 	fn write(&mut self, token: &Token) {
-		match token {
+		match *token {
 			Token::Literal(value) => {
-				let huffman_code = self.literal_tree[*value as usize];
+				let huffman_code = self.literal_tree[value as usize];
 				self.write_bits(huffman_code.code, huffman_code.length);
 			}
 			Token::Repeat(len, dist) => {
-				let (offset, extra_bits, code) = deflate_code_of_len(*len);
+				// the parsers only make repetitions deflate can encode, PackedToken checks it in debug builds
+				let (offset, extra_bits, code) = deflate_code_of_len(len).expect("invalid repetition length");
 				let huffman_code = self.literal_tree[code as usize];
 				self.write_bits(huffman_code.code, huffman_code.length);
 				self.write_bits(offset, extra_bits);
 
-				let (offset, extra_bits, code) = deflate_code_of_dist(*dist);
+				let (offset, extra_bits, code) = deflate_code_of_dist(dist).expect("invalid repetition distance");
 				let huffman_code = self.distance_tree[code as usize];
 				self.write_bits(huffman_code.code, huffman_code.length);
 				self.write_bits(offset, extra_bits);
@@ -83,12 +321,51 @@ impl<'a, T: Write> DeflateWriter<'a, T> {
 		};
 	}
 
-	fn new_fixed_codes_block(&mut self, is_final: bool) {
+	fn end_block(&mut self) {
 		if self.in_block {
-			// end of block
 			let huffman_code = self.literal_tree[256];
 			self.write_bits(huffman_code.code, huffman_code.length);
+			self.in_block = false;
+		}
+	}
+
+	fn pad_to_byte(&mut self) {
+		if self.curr_full_bits > 0 {
+			self.write_bytes(&[(self.curr_bytes & 0xFF) as u8]);
+			self.curr_bytes = 0;
+			self.curr_full_bits = 0;
+		}
+	}
+
+	fn sync_flush(&mut self) {
+		// end the current block with an empty stored block, which leaves the output byte aligned.
+		self.write_stored_block(false, &[]);
+	}
+
+	fn write_stored_blocks(&mut self, is_final: bool, data: &[u8]) {
+		// as many stored blocks as needed to hold data
+		let mut pieces = data.chunks(MAX_STORED_BLOCK_LEN).peekable();
+		if pieces.peek().is_none() {
+			self.write_stored_block(is_final, &[]);
 		}
+		while let Some(piece) = pieces.next() {
+			self.write_stored_block(is_final && pieces.peek().is_none(), piece);
+		}
+	}
+
+	fn write_stored_block(&mut self, is_final: bool, data: &[u8]) {
+		// data is copied as is, up to MAX_STORED_BLOCK_LEN bytes.
+		self.end_block();
+		self.write_bits(if is_final {1} else {0}, 1);
+		self.write_bits(0, 2); // stored
+		self.pad_to_byte();
+		self.write_bits(data.len() as u32, 16); // LEN
+		self.write_bits(!data.len() as u32 & 0xFFFF, 16); // NLEN
+		self.write_bytes(data);
+	}
+
+	fn new_fixed_codes_block(&mut self, is_final: bool) {
+		self.end_block();
 		self.in_block = true;
 		self.write_bits(if is_final {1} else {0}, 1);
 		self.write_bits(1, 1);
@@ -98,192 +375,286 @@ impl<'a, T: Write> DeflateWriter<'a, T> {
 	}
 
 	fn new_dynamic_codes_block(&mut self, is_final: bool, literal_code_lens: &[u8], distance_code_lens: &[u8]) {
-		if self.in_block {
-			// end of block
-			let huffman_code = self.literal_tree[256];
-			self.write_bits(huffman_code.code, huffman_code.length);
-		}
+		self.end_block();
 		self.in_block = true;
 		self.write_bits(if is_final {1} else {0}, 1);
 		self.write_bits(0, 1);
 		self.write_bits(1, 1);
 
 		let bit_writer = |bits, len| self.write_bits(bits, len);
-		create_dynamic_block_header(&literal_code_lens, &distance_code_lens, bit_writer);
+		create_dynamic_block_header(literal_code_lens, distance_code_lens, bit_writer);
 
 		self.literal_tree = huffman::calc_codes(literal_code_lens);
 		self.distance_tree = huffman::calc_codes(distance_code_lens);
 	}
 }
 
-fn create_dynamic_block_header(literal_code_lens: &[u8], distance_code_lens: &[u8], mut write_bits: impl FnMut(u32, u8) -> ()) {
-		// create a header (only the tree encoding part) for a new dynamic block with the given code lens
-		// write using a given Fn to enable usage for size calculation.
-
-		const CODE_LEN_OF_CODE_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
-
-		let mut rle_of_code_lens: Vec<[u8; 2]> = Vec::with_capacity(286 + 30); // vec of (val, length)
-		let code_lens_to_encode = literal_code_lens.iter().chain(distance_code_lens.iter());
-		for x in code_lens_to_encode {
-			// continue run if possible
-			let last_index = rle_of_code_lens.len() - 1;
-			if !rle_of_code_lens.is_empty() &&
-			rle_of_code_lens[last_index][0] == *x {
-				rle_of_code_lens[last_index][1] += 1;
-			} else {
-				// new run
-				rle_of_code_lens.push([*x, 1]);
-			}
-		}
-		let mut deflate_encode_of_rle = Vec::with_capacity(286 + 30); // vec of (code, extra bit count, extra bits value)
-		for val_len in rle_of_code_lens {
-			let val = val_len[0];
-			let mut len = val_len[1];
-			if val == 0 {
-				while len > 0 {
-					if len >= 11 {
-						let encoded_run = if len <= 138 {len} else {138};
-						deflate_encode_of_rle.push((18, 7, encoded_run - 11));
-						len -= encoded_run;
-					} else if len >= 3 {
-						deflate_encode_of_rle.push((17, 3, len - 3));
-						len = 0;
-					} else {
-						deflate_encode_of_rle.push((0, 0, 0));
-						len -= 1;
-					}
-				}
-			} else {
-				deflate_encode_of_rle.push((val, 0, 0));
-				len -= 1;
-				while len > 0 {
-					if len >= 3 {
-						let encoded_run = if len <= 6 {len} else {6};
-						deflate_encode_of_rle.push((16, 2, encoded_run - 3));
-						len -= encoded_run;
-					} else {
-						deflate_encode_of_rle.push((val, 0, 0));
-						len -= 1;
-					}
-				}
-			}
-		}
-		let mut count_of_code_len_code: [u64; 19] = [0; 19]; // how many times each code len code is used
-		for (code, _, _) in &deflate_encode_of_rle {
+fn create_dynamic_block_header(literal_code_lens: &[u8], distance_code_lens: &[u8], mut write_bits: impl FnMut(u32, u8)) {
+	// create a header (only the tree encoding part) for a new dynamic block with the given code lens
+	// write using a given Fn to enable usage for size calculation.
+
+	// trailing unused codes can be left out, down to the minimal counts
+	let literal_count = std::cmp::max(257, literal_code_lens.iter().rposition(|&l| l != 0).map_or(0, |i| i + 1));
+	let distance_count = std::cmp::max(1, distance_code_lens.iter().rposition(|&l| l != 0).map_or(0, |i| i + 1));
+	let code_lens: Vec<u8> = literal_code_lens[..literal_count].iter()
+		.chain(&distance_code_lens[..distance_count])
+		.copied()
+		.collect();
+
+	let (encoded_code_lens, code_len_of_code) = encode_code_lens(&code_lens);
+	let code_len_tree = huffman::calc_codes(&code_len_of_code);
+	let code_len_code_count = code_len_code_count(&code_len_of_code);
+
+	write_bits(literal_count as u32 - 257, 5); // HLIT
+	write_bits(distance_count as u32 - 1, 5); // HDIST
+	write_bits(code_len_code_count as u32 - 4, 4); // HCLEN
+	for &code in &CODE_LEN_OF_CODE_ORDER[..code_len_code_count] { // code lengths for the code length alphabet
+		write_bits(code_len_of_code[code] as u32, 3);
+	}
+	for (code, extra_bit_count, extra_bits_value) in encoded_code_lens {
+		let huffman_code = code_len_tree[code as usize];
+		write_bits(huffman_code.code, huffman_code.length);
+		write_bits(extra_bits_value as u32, extra_bit_count);
+	}
+}
+
+type EncodedCodeLen = (u8, u8, u8); // (code, extra bit count, extra bits value)
+
+fn encode_code_lens(code_lens: &[u8]) -> (Vec<EncodedCodeLen>, [u8; 19]) {
+	// finds the cheapest encoding of code_lens with the code length alphabet: the lengths themselves,
+	// 16 (repeat the previous length 3-6 times), 17 (3-10 zeros) and 18 (11-138 zeros).
+	// returns it and the code lengths of the code length alphabet.
+	// what is cheapest depends on the code length codes, which depend on what is used,
+	// so this alternates between the two while the header gets smaller.
+	const ROUNDS: usize = 8;
+
+	let mut best: (u64, Vec<EncodedCodeLen>, [u8; 19]) = (u64::MAX, vec![], [0; 19]);
+	let mut costs = [4; 19]; // start with equally long codes
+	for _ in 0..ROUNDS {
+		let encoded = encode_code_lens_with_costs(code_lens, &costs);
+		let mut count_of_code_len_code = [0; 19];
+		for (code, _, _) in &encoded {
 			count_of_code_len_code[*code as usize] += 1;
 		}
-		let mut code_len_of_code: [u8; 19] = [0; 19];
+		let mut code_len_of_code = [0; 19];
 		huffman::gen_lengths(&count_of_code_len_code, 7, &mut code_len_of_code);
-		let code_len_tree = huffman::calc_codes(&code_len_of_code);
 
-		write_bits(286 - 257, 5); // HLIT
-		write_bits(30 - 1, 5); // HDIST
-		write_bits(19 - 4, 4); // HCLEN
-		for i in 0..19 { // code lengths for the code length alphabet
-			write_bits(code_len_of_code[CODE_LEN_OF_CODE_ORDER[i]] as u32, 3);
+		let cost = 3 * code_len_code_count(&code_len_of_code) as u64 + encoded.iter()
+			.map(|(code, extra_bit_count, _)| (code_len_of_code[*code as usize] + extra_bit_count) as u64)
+			.sum::<u64>();
+		if cost >= best.0 {
+			break;
 		}
-		for (val, extra_bit_count, extra_bits_value) in deflate_encode_of_rle {
-			let huffman_code = code_len_tree[val as usize];
-			write_bits(huffman_code.code, huffman_code.length);
-			write_bits(extra_bits_value as u32, extra_bit_count);
+		for (c, &len) in costs.iter_mut().zip(&code_len_of_code) {
+			// an unused code would get a code once used, assume a long one
+			*c = if len == 0 {7} else {len as u64};
 		}
+		best = (cost, encoded, code_len_of_code);
 	}
+	let (_, encoded, code_len_of_code) = best;
+	(encoded, code_len_of_code)
+}
 
-impl<'a, T: Write> Drop for DeflateWriter<'a, T> {
-	fn drop(&mut self) {
-		// end of block
-		let huffman_code = self.literal_tree[256];
-		self.write_bits(huffman_code.code, huffman_code.length);
-		if self.curr_full_bits > 0 {
-			self.out.write_all(&[(self.curr_bytes & 0xFF) as u8]).unwrap();
+fn encode_code_lens_with_costs(code_lens: &[u8], costs: &[u64; 19]) -> Vec<EncodedCodeLen> {
+	// shortest path over code_lens, costs are the bits of every code length code without extra bits.
+	let n = code_lens.len();
+	// cheapest[i] is the (cost, code, run) of the cheapest encoding of code_lens[i..] and its first code
+	let mut cheapest = vec![(0, 0, 0); n + 1];
+	let mut same_run = 0; // how many lengths from i on equal code_lens[i]
+	for i in (0..n).rev() {
+		let val = code_lens[i];
+		same_run = if i + 1 < n && code_lens[i + 1] == val {same_run + 1} else {1};
+
+		let mut best = (costs[val as usize] + cheapest[i + 1].0, val, 1);
+		if val == 0 {
+			for run in 3..=std::cmp::min(same_run, 10) {
+				best = std::cmp::min(best, (costs[17] + 3 + cheapest[i + run].0, 17, run));
+			}
+			for run in 11..=std::cmp::min(same_run, 138) {
+				best = std::cmp::min(best, (costs[18] + 7 + cheapest[i + run].0, 18, run));
+			}
 		}
+		if i > 0 && code_lens[i - 1] == val {
+			// 16 repeats the length before it, however that was encoded
+			for run in 3..=std::cmp::min(same_run, 6) {
+				best = std::cmp::min(best, (costs[16] + 2 + cheapest[i + run].0, 16, run));
+			}
+		}
+		cheapest[i] = best;
+	}
+
+	let mut encoded = vec![];
+	let mut i = 0;
+	while i < n {
+		let (_, code, run) = cheapest[i];
+		encoded.push(match code {
+			16 => (16, 2, run as u8 - 3),
+			17 => (17, 3, run as u8 - 3),
+			18 => (18, 7, run as u8 - 11),
+			_ => (code, 0, 0),
+		});
+		i += run;
 	}
+	encoded
+}
+
+fn code_len_code_count(code_len_of_code: &[u8; 19]) -> usize {
+	// how many code lengths of the code length alphabet the header needs (HCLEN + 4),
+	// trailing zeros in CODE_LEN_OF_CODE_ORDER are left out
+	let used = CODE_LEN_OF_CODE_ORDER.iter().rposition(|&code| code_len_of_code[code] != 0).map_or(0, |i| i + 1);
+	std::cmp::max(4, used)
 }
 
-fn deflate_code_of_len(len: u32) -> (u32, u8, u32) {
-	// returns (offset, extra bits, code)
-
-	const LEN_TO_CODE: [(u32, u32, u8, u32); 29] = [
-	// (len start, len end, extra bits, code)
-	(3  , 4  , 0, 257),
-	(4  , 5  , 0, 258),
-	(5  , 6  , 0, 259),
-	(6  , 7  , 0, 260),
-	(7  , 8  , 0, 261),
-	(8  , 9  , 0, 262),
-	(9  , 10 , 0, 263),
-	(10 , 11 , 0, 264),
-	(11 , 13 , 1, 265),
-	(13 , 15 , 1, 266),
-	(15 , 17 , 1, 267),
-	(17 , 19 , 1, 268),
-	(19 , 23 , 2, 269),
-	(23 , 27 , 2, 270),
-	(27 , 31 , 2, 271),
-	(31 , 35 , 2, 272),
-	(35 , 43 , 3, 273),
-	(43 , 51 , 3, 274),
-	(51 , 59 , 3, 275),
-	(59 , 67 , 3, 276),
-	(67 , 83 , 4, 277),
-	(83 , 99 , 4, 278),
-	(99 , 115, 4, 279),
-	(115, 131, 4, 280),
-	(131, 163, 5, 281),
-	(163, 195, 5, 282),
-	(195, 227, 5, 283),
-	(227, 258, 5, 284),
-	(258, 259, 0, 285)
-	];
+fn deflate_code_of_len(len: u32) -> Option<(u32, u8, u32)> {
+	// returns (offset, extra bits, code), None if len can't be encoded
+
+	if len < 3 {
+		return None;
+	}
 	for (len_start, len_end, extra_bits, code) in &LEN_TO_CODE {
 		if len < *len_end {
-			return (len - len_start, *extra_bits, *code);
+			return Some((len - len_start, *extra_bits, *code));
 		}
 	}
-	panic!("invalid len");
+	None
 }
 
-fn deflate_code_of_dist(dist: u32) -> (u32, u8, u32) {
-	// returns (offset, extra bits, code)
-
-	const DIST_TO_CODE: [(u32, u32, u8, u32); 30] = [
-	// (dist start, dist end, extra bits, code)
-	(1    , 2    , 0 , 0 ),
-	(2    , 3    , 0 , 1 ),
-	(3    , 4    , 0 , 2 ),
-	(4    , 5    , 0 , 3 ),
-	(5    , 7    , 1 , 4 ),
-	(7    , 9    , 1 , 5 ),
-	(9    , 13   , 2 , 6 ),
-	(13   , 17   , 2 , 7 ),
-	(17   , 25   , 3 , 8 ),
-	(25   , 33   , 3 , 9 ),
-	(33   , 49   , 4 , 10),
-	(49   , 65   , 4 , 11),
-	(65   , 97   , 5 , 12),
-	(97   , 129  , 5 , 13),
-	(129  , 193  , 6 , 14),
-	(193  , 257  , 6 , 15),
-	(257  , 385  , 7 , 16),
-	(385  , 513  , 7 , 17),
-	(513  , 769  , 8 , 18),
-	(769  , 1025 , 8 , 19),
-	(1025 , 1537 , 9 , 20),
-	(1537 , 2049 , 9 , 21),
-	(2049 , 3073 , 10, 22),
-	(3073 , 4097 , 10, 23),
-	(4097 , 6145 , 11, 24),
-	(6145 , 8193 , 11, 25),
-	(8193 , 12289, 12, 26),
-	(12289, 16385, 12, 27),
-	(16385, 24577, 13, 28),
-	(24577, 32769, 13, 29)
-	];
+fn deflate_code_of_dist(dist: u32) -> Option<(u32, u8, u32)> {
+	// returns (offset, extra bits, code), None if dist can't be encoded
 
+	if dist < 1 {
+		return None;
+	}
 	for (dist_start, dist_end, extra_bits, code) in &DIST_TO_CODE {
 		if dist < *dist_end {
-			return (dist - dist_start, *extra_bits, *code);
+			return Some((dist - dist_start, *extra_bits, *code));
+		}
+	}
+	None
+}
+
+// order in which the code lengths of the code length alphabet are stored in a dynamic block header
+pub(crate) const CODE_LEN_OF_CODE_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+pub(crate) const LEN_TO_CODE: [(u32, u32, u8, u32); 29] = [
+// (len start, len end, extra bits, code)
+(3  , 4  , 0, 257),
+(4  , 5  , 0, 258),
+(5  , 6  , 0, 259),
+(6  , 7  , 0, 260),
+(7  , 8  , 0, 261),
+(8  , 9  , 0, 262),
+(9  , 10 , 0, 263),
+(10 , 11 , 0, 264),
+(11 , 13 , 1, 265),
+(13 , 15 , 1, 266),
+(15 , 17 , 1, 267),
+(17 , 19 , 1, 268),
+(19 , 23 , 2, 269),
+(23 , 27 , 2, 270),
+(27 , 31 , 2, 271),
+(31 , 35 , 2, 272),
+(35 , 43 , 3, 273),
+(43 , 51 , 3, 274),
+(51 , 59 , 3, 275),
+(59 , 67 , 3, 276),
+(67 , 83 , 4, 277),
+(83 , 99 , 4, 278),
+(99 , 115, 4, 279),
+(115, 131, 4, 280),
+(131, 163, 5, 281),
+(163, 195, 5, 282),
+(195, 227, 5, 283),
+(227, 258, 5, 284),
+(258, 259, 0, 285)
+];
+
+pub(crate) const DIST_TO_CODE: [(u32, u32, u8, u32); 30] = [
+// (dist start, dist end, extra bits, code)
+(1    , 2    , 0 , 0 ),
+(2    , 3    , 0 , 1 ),
+(3    , 4    , 0 , 2 ),
+(4    , 5    , 0 , 3 ),
+(5    , 7    , 1 , 4 ),
+(7    , 9    , 1 , 5 ),
+(9    , 13   , 2 , 6 ),
+(13   , 17   , 2 , 7 ),
+(17   , 25   , 3 , 8 ),
+(25   , 33   , 3 , 9 ),
+(33   , 49   , 4 , 10),
+(49   , 65   , 4 , 11),
+(65   , 97   , 5 , 12),
+(97   , 129  , 5 , 13),
+(129  , 193  , 6 , 14),
+(193  , 257  , 6 , 15),
+(257  , 385  , 7 , 16),
+(385  , 513  , 7 , 17),
+(513  , 769  , 8 , 18),
+(769  , 1025 , 8 , 19),
+(1025 , 1537 , 9 , 20),
+(1537 , 2049 , 9 , 21),
+(2049 , 3073 , 10, 22),
+(3073 , 4097 , 10, 23),
+(4097 , 6145 , 11, 24),
+(6145 , 8193 , 11, 25),
+(8193 , 12289, 12, 26),
+(12289, 16385, 12, 27),
+(16385, 24577, 13, 28),
+(24577, 32769, 13, 29)
+];
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::options::Level;
+
+	fn distance_code_lens(data: &[u8]) -> Vec<[u8; 30]> {
+		// of the dynamic blocks level 1 splits data into
+		let params = match Level::FAST.strategy() {
+			Strategy::Greedy(params) => params,
+			_ => unreachable!(),
+		};
+		let tokens = greedy::greedy(data, 0, params);
+		block_splitter::block_split(&tokens, data).iter().filter_map(|block| match block {
+			Block::DynamicCodes { distance_code_lens, .. } => Some(*distance_code_lens),
+			_ => None,
+		}).collect()
+	}
+
+	fn round_trips(data: &[u8]) {
+		for level in [1, 9] {
+			let options = CompressionOptions::default().level(Level::new(level).unwrap());
+			let compressed = crate::deflate_raw_with_options(data, options).unwrap();
+			assert_eq!(crate::inflate_raw(&compressed).unwrap(), data, "level {}", level);
+		}
+	}
+
+	#[test]
+	fn block_without_repetitions() {
+		// a de bruijn sequence: every 3 bytes from a to d once, so there is nothing to repeat and no distance code is used
+		let mut data = vec![b'a'; 2];
+		let mut seen = std::collections::HashSet::new();
+		while let Some(c) = (b'a'..=b'd').rev().find(|&c| !seen.contains(&[data[data.len() - 2], data[data.len() - 1], c])) {
+			seen.insert([data[data.len() - 2], data[data.len() - 1], c]);
+			data.push(c);
+		}
+		let blocks = distance_code_lens(&data);
+		assert_eq!(blocks.len(), 1);
+		assert_eq!(blocks[0][..2], [1, 1]);
+		assert!(blocks[0][2..].iter().all(|&len| len == 0));
+		round_trips(&data);
+	}
+
+	#[test]
+	fn block_with_one_distance_code() {
+		// every repetition is 3 back
+		let data = b"abc".repeat(10000);
+		let blocks = distance_code_lens(&data);
+		assert!(!blocks.is_empty());
+		for lens in blocks {
+			assert_eq!(lens[2], 1); // distance 3
+			assert_eq!(lens.iter().filter(|&&len| len != 0).count(), 2);
 		}
+		round_trips(&data);
 	}
-	panic!("invalid dist");
 }
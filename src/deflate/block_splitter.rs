@@ -1,153 +1,260 @@
-use super::{Token, deflate_code_of_len, deflate_code_of_dist};
+use super::{Token, PackedToken, deflate_code_of_len, deflate_code_of_dist};
 use crate::huffman;
 use crate::deflate;
 
+#[allow(clippy::large_enum_variant)]
 pub enum Block<'a> {
-	FixedCodes { tokens: &'a[Token] },
-	DynamicCodes { tokens: &'a[Token], literal_code_lens: [u8; 286], distance_code_lens: [u8; 30] },
+	Stored { tokens: &'a[PackedToken], data: &'a[u8] },
+	FixedCodes { tokens: &'a[PackedToken] },
+	DynamicCodes { tokens: &'a[PackedToken], literal_code_lens: [u8; 286], distance_code_lens: [u8; 30] },
+}
+
+impl Block<'_> {
+	pub fn tokens(&self) -> impl Iterator<Item = Token> + '_ {
+		let tokens = match self {
+			Block::Stored { tokens, .. } => tokens,
+			Block::FixedCodes { tokens } => tokens,
+			Block::DynamicCodes { tokens, .. } => tokens,
+		};
+		tokens.iter().map(|t| t.get())
+	}
+
+	pub fn code_lens(&self) -> (&[u8], &[u8]) {
+		// (literal code lens, distance code lens) the block is written with.
+		// a stored block has none, the fixed ones are about as costly.
+		match self {
+			Block::Stored { .. } | Block::FixedCodes { .. } => (&huffman::LITERAL_FIXED_CODES, &huffman::DISTANCE_FIXED_CODES),
+			Block::DynamicCodes { literal_code_lens, distance_code_lens, .. } => (literal_code_lens, distance_code_lens),
+		}
+	}
+}
+
+#[derive(Clone, Copy)]
+enum BlockType {
+	Stored,
+	FixedCodes,
+	DynamicCodes,
 }
 
 struct BlockInProgress {
 	start: usize,
 	end: usize,
-	freqs: FreqCounter,
+	data_start: usize, // the block holds data[data_start..data_end]
+	data_end: usize,
 	literal_code_lens: [u8; 286],
 	distance_code_lens: [u8; 30],
 	cost: u64,
-	is_dynamic: bool,
+	block_type: BlockType,
 }
 
-pub fn block_split(tokens: &[Token]) -> Vec<Block> {
-	const BLOCK_SIZE: usize = 1024;
-
-	let mut blocks = vec![];
-
-	let mut curr_block = None;
-	for i in (0..tokens.len()).step_by(BLOCK_SIZE) {
-		let start = i;
-		let end = if i + BLOCK_SIZE < tokens.len() {i + BLOCK_SIZE} else {tokens.len()};
-
-		let next_block = BlockInProgress::new(start, end, tokens);
-		match curr_block {
-			None => curr_block = Some(next_block),
-			Some(b) => {
-				let combined_block = BlockInProgress::merge(&b, &next_block);
-				if combined_block.cost < b.cost + next_block.cost {
-					curr_block = Some(combined_block);
-				} else {
-					blocks.push(build_block(b, tokens));
-					curr_block = Some(next_block);
-				}
+pub fn block_split<'a>(tokens: &'a [PackedToken], data: &'a [u8]) -> Vec<Block<'a>> {
+	// data is what tokens encode.
+	// blocks may only start at UNIT_SIZE token boundaries. candidate split points are first found by
+	// recursively bisecting while that lowers the cost, then the cheapest partition of the tokens
+	// using any subset of the candidates is picked.
+	let splitter = Splitter::new(tokens);
+	let unit_count = splitter.unit_count();
+	if unit_count == 0 {
+		return vec![];
+	}
+
+	let mut candidates = vec![0, unit_count];
+	splitter.bisect(0, unit_count, &mut candidates);
+	candidates.sort_unstable();
+
+	let split_points = splitter.cheapest_partition(&candidates);
+	split_points.windows(2)
+		.map(|w| build_block(splitter.block(w[0], w[1]), tokens, data))
+		.collect()
+}
+
+const UNIT_SIZE: usize = 256; // tokens
+const SEARCH_POINTS: usize = 8; // split points tried per round when bisecting
+const MAX_PARTITION_SPAN: usize = 64; // the most candidates a block found by cheapest_partition can span
+
+struct Splitter {
+	token_count: usize,
+	prefix_freqs: Vec<FreqCounter>, // freqs of the first i units, without the end of block. about 5 bytes per token
+	prefix_data_len: Vec<usize>, // bytes encoded by the first i units
+}
+
+impl Splitter {
+	fn new(tokens: &[PackedToken]) -> Splitter {
+		let mut counter = FreqCounter::new();
+		counter.literal_count[256] = 0;
+		let mut data_len = 0;
+		let mut prefix_freqs = vec![counter.clone()];
+		let mut prefix_data_len = vec![0];
+		for unit in tokens.chunks(UNIT_SIZE) {
+			for t in unit.iter().map(|t| t.get()) {
+				counter.count(&t);
+				data_len += t.data_len();
 			}
+			prefix_freqs.push(counter.clone());
+			prefix_data_len.push(data_len);
 		}
+		Splitter { token_count: tokens.len(), prefix_freqs, prefix_data_len }
 	}
-	if let Some(b) = curr_block {
-		blocks.push(build_block(b, tokens));
+
+	fn unit_count(&self) -> usize {
+		self.prefix_freqs.len() - 1
 	}
-	blocks
-}
 
-impl BlockInProgress {
-	fn new(start: usize, end: usize, all_tokens: &[Token]) -> BlockInProgress {
-		let tokens = &all_tokens[start..end];
-		let mut counter = FreqCounter::new();
-		for t in tokens {
-			counter.count(t);
+	fn block(&self, start_unit: usize, end_unit: usize) -> BlockInProgress {
+		// the block of units[start_unit..end_unit]
+		let (first, last) = (&self.prefix_freqs[start_unit], &self.prefix_freqs[end_unit]);
+		let mut freqs = FreqCounter::new();
+		for (i, count) in freqs.literal_count.iter_mut().enumerate() {
+			*count += last.literal_count[i] - first.literal_count[i];
 		}
-		let mut literal_code_lens = [0; 286];
-		let mut distance_code_lens = [0; 30];
-		huffman::gen_lengths(&counter.literal_count, 15, &mut literal_code_lens);
-		huffman::gen_lengths(&counter.distance_count, 15, &mut distance_code_lens);
+		for (i, count) in freqs.distance_count.iter_mut().enumerate() {
+			*count = last.distance_count[i] - first.distance_count[i];
+		}
+		freqs.extra_bits = last.extra_bits - first.extra_bits;
 
-		let dynamic_cost = block_cost(&counter, &literal_code_lens, &distance_code_lens) + dynamic_header_cost(&literal_code_lens, &distance_code_lens);
-		let fixed_cost = 3 + block_cost(&counter, &huffman::LITERAL_FIXED_CODES, &huffman::DISTANCE_FIXED_CODES);
+		let start = start_unit * UNIT_SIZE;
+		let end = (end_unit * UNIT_SIZE).min(self.token_count);
+		BlockInProgress::with_freqs(start, end, self.prefix_data_len[start_unit], self.prefix_data_len[end_unit], freqs)
+	}
 
-		let (cost, is_dynamic) = if dynamic_cost < fixed_cost {
-			(dynamic_cost, true)
-		} else {
-			(fixed_cost, false)
-		};
+	fn cost(&self, start_unit: usize, end_unit: usize) -> u64 {
+		self.block(start_unit, end_unit).cost
+	}
 
-		BlockInProgress {
-			start,
-			end,
-			freqs: counter,
-			literal_code_lens,
-			distance_code_lens,
-			cost,
-			is_dynamic,
+	fn bisect(&self, start_unit: usize, end_unit: usize, split_points: &mut Vec<usize>) {
+		// adds to split_points the best place to split units[start_unit..end_unit] in two,
+		// if there is one that is cheaper than a single block, and recurses into both halves.
+		if end_unit - start_unit < 2 {
+			return;
+		}
+		// costs aren't smooth in the split point, but coarse to fine search finds a good one quickly:
+		// try SEARCH_POINTS evenly spread points, then search again around the best.
+		let (mut low, mut high) = (start_unit + 1, end_unit - 1);
+		let mut best = (u64::MAX, low);
+		loop {
+			let step = std::cmp::max(1, (high - low) / SEARCH_POINTS);
+			for split in (low..=high).step_by(step) {
+				let cost = self.cost(start_unit, split) + self.cost(split, end_unit);
+				if cost < best.0 {
+					best = (cost, split);
+				}
+			}
+			if step == 1 {
+				break;
+			}
+			low = std::cmp::max(low, best.1.saturating_sub(step - 1));
+			high = std::cmp::min(high, best.1 + step - 1);
+		}
+
+		let (cost, split) = best;
+		if cost < self.cost(start_unit, end_unit) {
+			split_points.push(split);
+			self.bisect(start_unit, split, split_points);
+			self.bisect(split, end_unit, split_points);
 		}
 	}
 
-	fn merge(b1: &BlockInProgress, b2: &BlockInProgress) -> BlockInProgress {
-		let mut literal_count = [0; 286];
-		let mut distance_count = [0; 30];
-		for i in 0..286 {
-			literal_count[i] = b1.freqs.literal_count[i] + b2.freqs.literal_count[i];
+	fn cheapest_partition(&self, candidates: &[usize]) -> Vec<usize> {
+		// candidates are sorted unit indices, from 0 to unit_count.
+		// returns the subset of them (with both ends) splitting into the cheapest blocks.
+		// best[j] is the (cost, previous split) of the cheapest way to encode units[..candidates[j]].
+		let mut best = vec![(0, 0); candidates.len()];
+		for j in 1..candidates.len() {
+			best[j] = (j.saturating_sub(MAX_PARTITION_SPAN)..j)
+				.map(|i| (best[i].0 + self.cost(candidates[i], candidates[j]), i))
+				.min()
+				.unwrap();
 		}
-		for i in 0..30 {
-			distance_count[i] = b1.freqs.distance_count[i] + b2.freqs.distance_count[i];
+
+		let mut split_points = vec![];
+		let mut j = candidates.len() - 1;
+		while j != 0 {
+			split_points.push(candidates[j]);
+			j = best[j].1;
 		}
-		literal_count[256] = 1; // only one end of block symbol
-		let freqs = FreqCounter { literal_count, distance_count };
+		split_points.push(0);
+		split_points.reverse();
+		split_points
+	}
+}
 
+impl BlockInProgress {
+	fn with_freqs(start: usize, end: usize, data_start: usize, data_end: usize, freqs: FreqCounter) -> BlockInProgress {
 		let mut literal_code_lens = [0; 286];
 		let mut distance_code_lens = [0; 30];
-		huffman::gen_lengths(&freqs.literal_count, 15, &mut literal_code_lens);
-		huffman::gen_lengths(&freqs.distance_count, 15, &mut distance_code_lens);
+		huffman::gen_lengths(&freqs.literal_count.map(u64::from), 15, &mut literal_code_lens);
+		huffman::gen_lengths(&freqs.distance_count.map(u64::from), 15, &mut distance_code_lens);
 
-		let dynamic_cost = block_cost(&freqs, &literal_code_lens, &distance_code_lens) + dynamic_header_cost(&literal_code_lens, &distance_code_lens);
+		let dynamic_cost = 3 + block_cost(&freqs, &literal_code_lens, &distance_code_lens) + dynamic_header_cost(&literal_code_lens, &distance_code_lens);
 		let fixed_cost = 3 + block_cost(&freqs, &huffman::LITERAL_FIXED_CODES, &huffman::DISTANCE_FIXED_CODES);
+		let stored_cost = stored_cost(data_end - data_start);
 
-		let (cost, is_dynamic) = if dynamic_cost < fixed_cost {
-			(dynamic_cost, true)
+		let (cost, block_type) = if stored_cost <= dynamic_cost && stored_cost <= fixed_cost {
+			(stored_cost, BlockType::Stored)
+		} else if dynamic_cost < fixed_cost {
+			(dynamic_cost, BlockType::DynamicCodes)
 		} else {
-			(fixed_cost, false)
+			(fixed_cost, BlockType::FixedCodes)
 		};
 
 		BlockInProgress {
-			start: b1.start,
-			end: b2.end,
-			freqs,
+			start,
+			end,
+			data_start,
+			data_end,
 			literal_code_lens,
 			distance_code_lens,
 			cost,
-			is_dynamic,
+			block_type,
 		}
 	}
 }
 
-fn build_block(block: BlockInProgress, all_tokens: &[Token]) -> Block {
-	if block.is_dynamic {
-		Block::DynamicCodes {
-			tokens: &all_tokens[block.start..block.end],
+fn build_block<'a>(block: BlockInProgress, all_tokens: &'a [PackedToken], data: &'a [u8]) -> Block<'a> {
+	let tokens = &all_tokens[block.start..block.end];
+	match block.block_type {
+		BlockType::Stored => Block::Stored {
+			tokens,
+			data: &data[block.data_start..block.data_end],
+		},
+		BlockType::FixedCodes => Block::FixedCodes {
+			tokens,
+		},
+		BlockType::DynamicCodes => Block::DynamicCodes {
+			tokens,
 			literal_code_lens: block.literal_code_lens,
 			distance_code_lens: block.distance_code_lens,
-		}
-	} else {
-		Block::FixedCodes {
-			tokens: &all_tokens[block.start..block.end],
-		}
+		},
 	}
 }
 
+fn stored_cost(data_len: usize) -> u64 {
+	// a stored block holds up to MAX_STORED_BLOCK_LEN bytes, more need a few blocks.
+	// each takes 3 header bits, padding to the next byte (7 bits at worst), LEN and NLEN.
+	let block_count = std::cmp::max(1, data_len.div_ceil(deflate::MAX_STORED_BLOCK_LEN));
+	(block_count * (3 + 7 + 32) + data_len * 8) as u64
+}
+
 fn dynamic_header_cost(literal_code_lens: &[u8], distance_code_lens: &[u8]) -> u64 {
 	let mut res: u64 = 0;
 	let incrementor = |_bits: u32, len: u8| res += len as u64;
-	deflate::create_dynamic_block_header(&literal_code_lens, &distance_code_lens, incrementor);
+	deflate::create_dynamic_block_header(literal_code_lens, distance_code_lens, incrementor);
 	res
 }
 
 fn block_cost(counter: &FreqCounter, literal_code_lens: &[u8], distance_code_lens: &[u8]) -> u64 {
-	let mut total = 0;
-	total += counter.literal_count.iter().zip(literal_code_lens.iter()).map(|(a, b)| a * (*b as u64)).sum::<u64>();
-	total += counter.distance_count.iter().zip(distance_code_lens.iter()).map(|(a, b)| a * (*b as u64)).sum::<u64>();
+	let mut total = counter.extra_bits as u64;
+	total += counter.literal_count.iter().zip(literal_code_lens.iter()).map(|(a, b)| *a as u64 * *b as u64).sum::<u64>();
+	total += counter.distance_count.iter().zip(distance_code_lens.iter()).map(|(a, b)| *a as u64 * *b as u64).sum::<u64>();
 	total
 }
 
+#[derive(Clone)]
 struct FreqCounter {
-	literal_count: [u64; 286],
-	distance_count: [u64; 30],
+	// u32 is plenty for the tokens of a chunk, and halves the splitter's prefix counts
+	literal_count: [u32; 286],
+	distance_count: [u32; 30],
+	extra_bits: u32, // total extra bits of lengths and distances
 }
 
 impl FreqCounter {
@@ -155,6 +262,7 @@ impl FreqCounter {
 		let mut res = FreqCounter {
 			literal_count: [0; 286],
 			distance_count: [0; 30],
+			extra_bits: 0,
 		};
 		res.literal_count[256] = 1; // end of block
 		res
@@ -164,10 +272,12 @@ impl FreqCounter {
 		match token {
 			Token::Literal(value) => self.literal_count[*value as usize] += 1,
 			Token::Repeat(len, dist) => {
-				let (_offset, _extra_bits, code) = deflate_code_of_len(*len);
+				let (_offset, extra_bits, code) = deflate_code_of_len(*len).expect("invalid repetition length");
 				self.literal_count[code as usize] += 1;
-				let (_offset, _extra_bits, code) = deflate_code_of_dist(*dist);
+				self.extra_bits += extra_bits as u32;
+				let (_offset, extra_bits, code) = deflate_code_of_dist(*dist).expect("invalid repetition distance");
 				self.distance_count[code as usize] += 1;
+				self.extra_bits += extra_bits as u32;
 			}
 		}
 	}
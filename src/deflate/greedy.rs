@@ -0,0 +1,70 @@
+use crate::deflate::{Token, PackedToken};
+use crate::options::MatchParams;
+use super::match_finder::HashChain;
+
+pub fn greedy(data: &[u8], start: usize, params: MatchParams) -> Vec<PackedToken> {
+	// encodes data[start..] taking the longest match at every position, like zlib's fast levels.
+	let mut chain = HashChain::new(data, start);
+	let mut tokens = vec![];
+	let mut pos = start;
+	while pos < data.len() {
+		let (len, dist) = chain.longest_match(pos, 0, params.max_chain, params.nice_len);
+		if len >= 3 {
+			tokens.push(Token::Repeat(len as u32, dist as u32).into());
+			if len <= params.max_lazy {
+				for p in pos..pos + len {
+					chain.insert(p);
+				}
+			} else {
+				// long matches are skipped without indexing, for speed
+				chain.insert(pos);
+			}
+			pos += len;
+		} else {
+			tokens.push(Token::Literal(data[pos]).into());
+			chain.insert(pos);
+			pos += 1;
+		}
+	}
+	tokens
+}
+
+pub fn lazy(data: &[u8], start: usize, params: MatchParams) -> Vec<PackedToken> {
+	// encodes data[start..] like zlib's default levels:
+	// a match is only taken if the position after it doesn't start a longer one, otherwise a literal is emitted.
+	let mut chain = HashChain::new(data, start);
+	let mut tokens = vec![];
+	let mut pos = start;
+	let mut pending: Option<(usize, usize)> = None; // best (len, dist) found at pos - 1, not emitted yet
+	while pos < data.len() {
+		let (len, dist) = match pending {
+			Some((prev_len, _)) if prev_len >= params.max_lazy => (0, 0), // good enough, don't look further
+			Some((prev_len, _)) if prev_len >= params.good_len => chain.longest_match(pos, prev_len, params.max_chain / 4, params.nice_len),
+			Some((prev_len, _)) => chain.longest_match(pos, prev_len, params.max_chain, params.nice_len),
+			None => chain.longest_match(pos, 0, params.max_chain, params.nice_len),
+		};
+		chain.insert(pos);
+
+		match pending {
+			Some((prev_len, prev_dist)) if prev_len >= 3 && len <= prev_len => {
+				// the match at pos - 1 wins
+				tokens.push(Token::Repeat(prev_len as u32, prev_dist as u32).into());
+				for p in pos + 1..pos - 1 + prev_len {
+					chain.insert(p);
+				}
+				pos = pos - 1 + prev_len;
+				pending = None;
+				continue;
+			}
+			Some(_) => tokens.push(Token::Literal(data[pos - 1]).into()),
+			None => {}
+		}
+		pending = Some((len, dist));
+		pos += 1;
+	}
+	if pending.is_some() {
+		// nothing can be repeated at the last byte
+		tokens.push(Token::Literal(data[pos - 1]).into());
+	}
+	tokens
+}
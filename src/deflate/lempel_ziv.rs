@@ -1,270 +1,307 @@
-use std::collections::{HashMap, VecDeque};
-use std::rc::Rc;
+use crate::deflate::{Token, PackedToken, deflate_code_of_len, deflate_code_of_dist};
+use crate::options::{FinderParams, MatchFinder};
+use super::match_finder::{HashChain, BinaryTree};
 
-use crate::deflate::{Token, deflate_code_of_len, deflate_code_of_dist};
+pub const MAX_REP_LEN: usize = 258; // max len supported by the deflate format
+pub const MAX_REP_DIST: usize = 32768; // max dist supported by the deflate format
 
-const MAX_REP_LEN: usize = 258; // max len supported by the deflate format
-const MAX_REP_DIST: usize = 32768; // max dist supported by the deflate format
+pub fn lempel_ziv(data: &[u8], start: usize, costs: &[BlockCosts], params: FinderParams) -> Vec<PackedToken> {
+	// encodes data[start..], data[..start] is only used as a dictionary for repetitions.
+	// token sizes are estimated using costs (ordered by end), or a fixed heuristic past the last one.
+	Encoder::new(data, start, costs, params).run()
+}
 
-pub fn lempel_ziv(data: &[u8]) -> Vec<Token> {
-	return Encoder::new(data).run();
+pub struct BlockCosts {
+	// size in bits of each symbol, for tokens starting before end (a position in data)
+	pub end: usize,
+	literal: [u64; 286],
+	distance: [u64; 30],
+	length: [u64; MAX_REP_LEN + 1], // of the code and extra bits of every repetition length
 }
 
-struct Encoder<'a> {
-	/*
-	Encodes the data in order.
-	The possible encodings are saved as a linked list of tokens,
-	that splits at any decision(which repetition/literal to use).
-	Example with 3 possible encodings:
+impl BlockCosts {
+	pub fn new(end: usize, literal_code_lens: &[u8], distance_code_lens: &[u8]) -> BlockCosts {
+		// symbols the block didn't use have no code, they are estimated as a little rarer than the rarest used one.
+		fn costs_of_lens<const N: usize>(code_lens: &[u8]) -> [u64; N] {
+			let unused_cost = code_lens.iter().max().map_or(15, |l| (*l + 1).min(15)) as u64;
+			let mut costs = [unused_cost; N];
+			for (cost, l) in costs.iter_mut().zip(code_lens) {
+				if *l > 0 {
+					*cost = *l as u64;
+				}
+			}
+			costs
+		}
+		let literal: [u64; 286] = costs_of_lens(literal_code_lens);
+		let mut length = [0; MAX_REP_LEN + 1];
+		for (len, cost) in length.iter_mut().enumerate().skip(3) {
+			if let Some((_offset, extra_bits, code)) = deflate_code_of_len(len as u32) {
+				*cost = literal[code as usize] + extra_bits as u64;
+			}
+		}
+		BlockCosts {
+			end,
+			literal,
+			distance: costs_of_lens(distance_code_lens),
+			length,
+		}
+	}
 
-           token2 - token3 - token4
-	      /
-	token1                                   option1 for next token
-	      \                                 /
-	       token2alternative - another token
-	                                        \
-	                                         options2 for next token
+	fn heuristic() -> BlockCosts {
+		// for when the huffman codes are not known yet
+		BlockCosts::new(usize::MAX, &[8; 286], &[5; 30])
+	}
 
-	At any moment, only the best possibilty for each length is stored.
-	If we find another, we keep the better one.
-	The list heads are saved in a ring buffer with length MAX_REP_LEN.
-	For every i from 0 to MAX_REP_LEN,
-	at possible_encodings[(i + pos) % MAX_REP_LEN], a tuple may be stored,
-	containing the best encoding for the first (i + pos) bytes (yet) and its estimated size (in bits).
+	fn distance_cost(&self, dist: usize) -> u64 {
+		match deflate_code_of_dist(dist as u32) {
+			Some((_offset, extra_bits, code)) => self.distance[code as usize] + extra_bits as u64,
+			None => u64::MAX / 2, // can't be encoded, never the cheaper choice
+		}
+	}
+}
+
+struct Encoder<'a> {
+	/*
+	Encodes the data in order, as a shortest path:
+	every position is reached from an earlier one by a literal or a repetition,
+	and costs the (estimated) bits of the cheapest way to get there.
+	Going forward, each position in turn tries every token that can start at it.
+	A token only replaces the one ending at the same position if it is strictly cheaper,
+	so on ties the earliest found is kept.
+	At the end, the tokens are read back from the end of the data.
 	*/
 	data: &'a[u8],
+	start: usize,
 	reps_tracker: RepsTracker<'a>,
-	possible_encodings: Vec<Option<(TokenList, u64)>>, // length of MAX_REP_LEN.
+	costs: &'a [BlockCosts],
+	curr_costs: usize, // index in costs of the block pos is in
+	heuristic_costs: BlockCosts, // past the last of costs
+	cheapest: Vec<u64>, // the size of the best encoding (yet) of data[start..start + i], u64::MAX if there is none
+	last_step: Vec<Step>, // the last token of that encoding
+}
+
+#[derive(Clone, Copy)]
+struct Step {
+	// a token without its literal value, which the data has
+	len: u16,
+	dist: u16, // 0 for a literal
 }
 
-impl Encoder<'_> {
-	fn run(mut self) -> Vec<Token> {
+impl<'a> Encoder<'a> {
+	fn run(mut self) -> Vec<PackedToken> {
 		// Return an encoding of the data using deflate::Token. (Literal bytes and repetitions).
-		if self.data.len() == 0 {
+		if self.data.len() == self.start {
 			return vec![];
 		}
-		self.possible_encodings[1] = Some((TokenList { token: Token::Literal(self.data[0]), prev: None }, 0));
+		self.find_cheapest();
+
+		let mut out = vec![];
+		let mut i = self.data.len() - self.start;
+		while i > 0 {
+			let step = self.last_step[i];
+			out.push(match step.dist {
+				0 => Token::Literal(self.data[self.start + i - 1]),
+				dist => Token::Repeat(step.len as u32, dist as u32),
+			}.into());
+			i -= step.len as usize;
+		}
+		out.reverse();
+
+		out
+	}
+
+	fn find_cheapest(&mut self) {
+		// fills cheapest and last_step, data[start..] must not be empty
+		let n = self.data.len() - self.start;
+		self.cheapest = vec![u64::MAX; n + 1];
+		self.last_step = vec![Step { len: 0, dist: 0 }; n + 1];
+		// the first byte is always a literal
+		self.cheapest[1] = 0;
+		self.last_step[1] = Step { len: 1, dist: 0 };
 		self.reps_tracker.advance();
 
 		while self.reps_tracker.pos < self.data.len() {
-			let i = self.reps_tracker.pos % MAX_REP_LEN;
-			let (curr_encoding, curr_size) = self.possible_encodings[i].take().unwrap();
-			let curr_encoding = Rc::new(curr_encoding);
+			while self.curr_costs < self.costs.len() && self.costs[self.curr_costs].end <= self.reps_tracker.pos {
+				self.curr_costs += 1;
+			}
+			let pos = self.reps_tracker.pos;
+			let reps = self.reps_tracker.get_reps();
+			let costs = self.costs.get(self.curr_costs).unwrap_or(&self.heuristic_costs);
+			let i = pos - self.start;
+			let curr_size = self.cheapest[i];
 
 			// could use a literal type token for next byte
-			self.insert_next(&curr_encoding, curr_size, Token::Literal(self.data[self.reps_tracker.pos]));
+			let size = curr_size + costs.literal[self.data[pos] as usize];
+			insert_next(&mut self.cheapest, &mut self.last_step, i + 1, size, Step { len: 1, dist: 0 });
 
-			// could use a repeat token for next bytes
-			for (dist, len) in self.reps_tracker.get_reps() {
-				self.insert_next(&curr_encoding, curr_size, Token::Repeat(len, dist as u32));
+			// could repeat any length a rep has, with the cheapest distance of the reps at least that long.
+			// going from the longest rep, which is the farthest, each one adds the lengths up to its own.
+			let mut cheapest_dist = (u64::MAX, 0);
+			for (k, &(dist, len)) in reps.iter().enumerate().rev() {
+				let dist_cost = costs.distance_cost(dist);
+				if dist_cost <= cheapest_dist.0 {
+					cheapest_dist = (dist_cost, dist); // the closer one on ties
+				}
+				let shorter_len = if k == 0 {2} else {reps[k - 1].1 as usize};
+				for len in shorter_len + 1..=len as usize {
+					let size = curr_size + costs.length[len] + cheapest_dist.0;
+					insert_next(&mut self.cheapest, &mut self.last_step, i + len, size, Step { len: len as u16, dist: cheapest_dist.1 as u16 });
+				}
 			}
 			self.reps_tracker.advance();
 		}
-
-		// only one list head should remain.
-		let list_head = self.possible_encodings[self.reps_tracker.pos % MAX_REP_LEN].take().unwrap();
-		let mut curr = list_head.0;
-		let mut out = vec![];
-
-		loop {
-			out.push(curr.token);
-			match curr.prev {
-				None => break,
-				Some(prev_ref) => curr = Rc::try_unwrap(prev_ref).unwrap_or_else(|_| panic!()),
-			}
-		}
-		out.reverse();
-
-		out
 	}
 
-	fn new(data: &[u8]) -> Encoder {
-		let mut possible_encodings = Vec::with_capacity(MAX_REP_LEN);
-		for _ in 0..MAX_REP_LEN {
-			possible_encodings.push(None);
-		}
+	fn new(data: &'a [u8], start: usize, costs: &'a [BlockCosts], params: FinderParams) -> Encoder<'a> {
 		Encoder {
 			data,
-			reps_tracker: RepsTracker::new(data),
-			possible_encodings,
-		}
-	}
-
-	fn insert_next(&mut self, curr_encoding: &Rc<TokenList>, curr_size: u64, next_token: Token) {
-		let extra_length = match next_token { Token::Literal(_) => 1, Token::Repeat(len, _dist) => len, };
-		let extra_size = size_of_token(&next_token);
-		let i = (self.reps_tracker.pos + extra_length as usize) % MAX_REP_LEN;
-		let should_insert = match &self.possible_encodings[i] {
-			None => true,
-			Some((_other, other_size)) => curr_size + extra_size < *other_size,
-		};
-		if should_insert {
-			let next_encoding = TokenList { token: next_token, prev: Some(Rc::clone(curr_encoding)) };
-			let next_size = curr_size + extra_size;
-			self.possible_encodings[i] = Some((next_encoding, next_size));
+			start,
+			reps_tracker: RepsTracker::new(data, start, params),
+			costs,
+			curr_costs: 0,
+			heuristic_costs: BlockCosts::heuristic(),
+			cheapest: vec![],
+			last_step: vec![],
 		}
 	}
 }
 
-struct TokenList {
-	token: Token,
-	prev: Option<Rc<TokenList>>,
-}
-
-fn size_of_token(token: &Token) -> u64 {
-	// heuristic for size. (depends on what the huffman codes will be).
-	match token {
-		Token::Literal(_) => 8,
-		Token::Repeat(len, dist) => ((deflate_code_of_len(*len).1 + 8) + (deflate_code_of_dist(*dist).1 + 5)) as u64,
+fn insert_next(cheapest: &mut [u64], last_step: &mut [Step], i: usize, size: u64, step: Step) {
+	// the encoding of data[start..start + i] ending with step is kept if it is the cheapest yet
+	if size < cheapest[i] {
+		cheapest[i] = size;
+		last_step[i] = step;
 	}
 }
 
-const HASH_WINDOW_SIZE: usize = 2 << 16;
-const HASH_AHEAD: usize = MAX_REP_LEN; // we need to calc a little ahead because some repetitions might continue to in front of reps_tracker.pos;
-
 struct RepsTracker<'a> {
-	data: &'a [u8],
-	pos: usize, // current position in the data
-	reps: HashMap<&'a [u8], VecDeque<usize>>, // maps [u8; 3] to their positions, closest in front, too far are discarded.
-	to_forget: VecDeque<&'a	[u8]>, // remember who was where so you could discard far ones. newest in front.
-	window_rolling_hash: [u32; HASH_WINDOW_SIZE], // remember hash of recently terminated(+a little in the future) data prefixes. cyclic. hash of data[0..x] in x % size.
+	// the match finder, moving through the data one position at a time
+	pos: usize, // current position in the data, everything before it is indexed
+	params: FinderParams,
+	finder: Finder<'a>,
+	pos_indexed: bool, // the binary tree indexes pos while searching it
+}
+
+enum Finder<'a> {
+	HashChain(HashChain<'a>),
+	BinaryTree(BinaryTree<'a>),
 }
 
 impl RepsTracker<'_> {
-	fn new(data: &[u8]) -> RepsTracker {
-		let mut s = RepsTracker {
-			data,
-			pos: 0,
-			reps: HashMap::new(),
-			to_forget: VecDeque::new(),
-			window_rolling_hash: [0; HASH_WINDOW_SIZE],
+	fn new(data: &[u8], start: usize, params: FinderParams) -> RepsTracker<'_> {
+		// data[..start] is indexed right away
+		let finder = match params.finder {
+			MatchFinder::HashChain => Finder::HashChain(HashChain::new(data, start)),
+			MatchFinder::BinaryTree => Finder::BinaryTree(BinaryTree::new(data, start, params.max_depth, params.nice_len)),
 		};
-		for i in 0..HASH_AHEAD {
-			s.window_rolling_hash[i + 1] = RepsTracker::extend_hash(s.window_rolling_hash[i], s.data[i]);
+		RepsTracker {
+			pos: start,
+			params,
+			finder,
+			pos_indexed: false,
 		}
-		s
 	}
 
 	fn advance(&mut self) {
-		if self.pos + 3 <= self.data.len() {
-			// add new ones
-			let curr = &self.data[self.pos..self.pos + 3];
-			let vec = match self.reps.get_mut(curr) {
-				Some(v) => v,
-				None => {self.reps.insert(curr, VecDeque::new()); self.reps.get_mut(curr).unwrap()},
-			};
-			vec.push_front(self.pos);
-			self.to_forget.push_front(curr);
-		}
-		if self.pos + HASH_AHEAD < self.data.len() {
-			let b = self.data[self.pos + HASH_AHEAD];
-			let prev_hash = self.window_rolling_hash[(self.pos + HASH_AHEAD) % HASH_WINDOW_SIZE];
-			let next_hash = RepsTracker::extend_hash(prev_hash, b);
-			self.window_rolling_hash[(self.pos + HASH_AHEAD + 1) % HASH_WINDOW_SIZE] = next_hash;
+		if !self.pos_indexed {
+			match &mut self.finder {
+				Finder::HashChain(chain) => chain.insert(self.pos),
+				Finder::BinaryTree(tree) => tree.insert(self.pos, self.params.max_depth, self.params.nice_len, None),
+			}
 		}
+		self.pos_indexed = false;
 		self.pos += 1;
-		if self.to_forget.len() > MAX_REP_DIST {
-			// discard old ones
-			let too_old = self.to_forget.pop_back().unwrap();
-			self.reps.get_mut(too_old).unwrap().pop_back();
-		} 
-	}
-
-	fn hash_recent_substring(&self, a: usize, b: usize) -> u32 {
-		// return hash of self.data[a..b]
-		// assumes the hashes of [..a], [..b] are still in the window/hash ahead.
-		self.window_rolling_hash[a % HASH_WINDOW_SIZE].rotate_left((b - a) as u32) ^ self.window_rolling_hash[b % HASH_WINDOW_SIZE]
-	}
-
-	fn extend_hash(hash: u32, b: u8) -> u32 {
-		hash.rotate_left(1) ^ BUZHASH_TABLE[b as usize]
 	}
 
-	fn get_reps(&self) -> Vec<(usize, u32)> {
+	fn get_reps(&mut self) -> Vec<(usize, u32)> {
 		// finds old occurrences of upcoming bytes.
 		// returns a Vec of tuples of the form: (rep dist, length)
 		// closer reps are first, only returns the closest one of each length
-		let max_len = |start: usize, min_len_to_care: usize| -> u32 {
-			// how many bytes of the sequence starting at start agree with the one at pos?
-			// if we are sure that the result < min_len_to_care then we can return 0
-
-			if unsafe { self.data.get_unchecked(start + min_len_to_care - 1) != self.data.get_unchecked(self.pos + min_len_to_care - 1) } {
-				// we definitely don't care. if this reads outside of data, then anyway a rep we care about can't exist.
-				return 0
-			}
-			// using hash to check if it is possible that we care
-			let hash1 = self.hash_recent_substring(start, start + min_len_to_care);
-			let hash2 = self.hash_recent_substring(self.pos, self.pos + min_len_to_care);
-			if hash1 != hash2 {
-				// we definitely don't care.
-				return 0
+		let mut out = vec![];
+		match &mut self.finder {
+			Finder::HashChain(chain) => chain.reps(self.pos, self.params.max_depth, self.params.nice_len, &mut out),
+			Finder::BinaryTree(tree) => {
+				tree.insert(self.pos, self.params.max_depth, self.params.nice_len, Some(&mut out));
+				self.pos_indexed = true;
 			}
+		}
+		out
+	}
+}
 
-			let mut res: usize = 3; // only called if 3 is already known
-			// bound check manually perfomed later. this is first because it is a lot more likely to return false
-			while unsafe { self.data.get_unchecked(start + res) == self.data.get_unchecked(self.pos + res) } &&
-			res < MAX_REP_LEN &&
-			self.pos + res < self.data.len() { // no need to check start + res since start < self.pos
-				res += 1;
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn full_length_only_cost(data: &[u8], start: usize, costs: &BlockCosts, params: FinderParams) -> u64 {
+		// the same search, but a rep can only be taken whole, at its own distance
+		let n = data.len() - start;
+		let mut cheapest = vec![u64::MAX; n + 1];
+		cheapest[1] = 0;
+		let mut reps_tracker = RepsTracker::new(data, start, params);
+		reps_tracker.advance();
+		while reps_tracker.pos < data.len() {
+			let i = reps_tracker.pos - start;
+			cheapest[i + 1] = cheapest[i + 1].min(cheapest[i] + costs.literal[data[reps_tracker.pos] as usize]);
+			for (dist, len) in reps_tracker.get_reps() {
+				let len = len as usize;
+				cheapest[i + len] = cheapest[i + len].min(cheapest[i] + costs.length[len] + costs.distance_cost(dist));
 			}
-			res as u32
-		};
-		if self.pos + 3 > self.data.len() {
-			return vec![]; // no room left for reps
+			reps_tracker.advance();
 		}
-		let reps = match self.reps.get(&self.data[self.pos..self.pos + 3]) {
-			Some(rep_deque) => rep_deque,
-			None => return vec![], // no reps
+		cheapest[n]
+	}
+
+	fn cost_of_tokens(tokens: &[PackedToken], costs: &BlockCosts) -> u64 {
+		// as the parse counts it, the first byte is free
+		tokens.iter().skip(1).map(|token| match token.get() {
+			Token::Literal(value) => costs.literal[value as usize],
+			Token::Repeat(len, dist) => costs.length[len as usize] + costs.distance_cost(dist as usize),
+		}).sum()
+	}
+
+	fn inputs() -> Vec<Vec<u8>> {
+		let mut x: u32 = 1;
+		let mut next = move || {
+			x = x.wrapping_mul(1103515245).wrapping_add(12345);
+			x >> 16
 		};
-		let mut out = vec![];
-		let mut longest = 0;
-		for start in reps {
-			let l = max_len(*start, longest as usize + 1);
-			if l > longest {
-				out.push((self.pos - *start, l));
-				longest = l;
-			}
-			if longest == MAX_REP_LEN as u32 {
-				break;
-			}
+		let words = ["the ", "quick ", "brown ", "fox ", "jumps ", "over ", "lazy ", "dog ", "and ", "runs "];
+		let text: Vec<u8> = (0..2000).flat_map(|_| words[next() as usize % words.len()].bytes()).collect();
+		let ab: Vec<u8> = (0..8000).map(|_| if next() & 1 == 0 {b'a'} else {b'b'}).collect();
+		let mut edited = b"fn main() { println!(\"hello\"); }\n".repeat(300);
+		for _ in 0..100 {
+			let i = next() as usize % edited.len();
+			edited[i] = next() as u8;
 		}
-		out
+		vec![text, ab, edited, vec![0; 5000]]
 	}
-}
 
+	#[test]
+	fn never_costlier_than_full_lengths_only() {
+		// any length of a rep at the cheapest distance of the reps as long includes every rep whole at its own distance,
+		// so the estimated cost can only go down.
+		let literal_lens: Vec<u8> = (0..286).map(|symbol| (5 + symbol % 8) as u8).collect();
+		let distance_lens: Vec<u8> = (0..30).map(|code| (code * 7 % 15 + 1) as u8).collect();
+		let mut improved = false;
+		for data in inputs() {
+			for start in [0, data.len() / 4] {
+				for costs in [BlockCosts::heuristic(), BlockCosts::new(usize::MAX, &literal_lens, &distance_lens)] {
+					for finder in [MatchFinder::HashChain, MatchFinder::BinaryTree] {
+						let params = FinderParams { finder, max_depth: usize::MAX, nice_len: usize::MAX };
+						let mut encoder = Encoder::new(&data, start, std::slice::from_ref(&costs), params);
+						encoder.find_cheapest();
+						let cost = encoder.cheapest[data.len() - start];
+						let reference = full_length_only_cost(&data, start, &costs, params);
+						assert!(cost <= reference, "{} > {}", cost, reference);
+						improved |= cost < reference;
 
-// random values.
-const BUZHASH_TABLE: [u32; 256] = [
-0x012e00ee, 0x186a4024, 0x1907cb61, 0x11ec7b23, 0x3ad46cc4, 0x71162b15, 0x0e249d5b, 0x4b3da547,
-0x47fcc0ba, 0x23563a52, 0x7ff790f7, 0x2e4fc914, 0x0be8bcb7, 0x46d8f54c, 0x74792284, 0x1b80331e,
-0x41b1ef25, 0x370de29e, 0x7182e115, 0x096b1c25, 0x206405cf, 0x27f90686, 0x1f8cfa96, 0x44a81987,
-0x0b68380a, 0x0ac9b87d, 0x141a7085, 0x1405d28d, 0x65f1bb41, 0x2df529c6, 0x79e53f7f, 0x7ae0b359,
-0x2067b2ac, 0x02f02fb1, 0x6e04c595, 0x7bc101a4, 0x4079bf1e, 0x00cf7d8e, 0x52b73c08, 0x5ea7809b,
-0x2c886d78, 0x2a100e94, 0x1b884f7d, 0x34fc924d, 0x35b645a6, 0x3330a761, 0x707d15cb, 0x6e1066e8,
-0x6b4dd29f, 0x5860b7ae, 0x054ee9d8, 0x4550a270, 0x06d8a15c, 0x17c8c917, 0x5cfdcccc, 0x1a560f8e,
-0x6f039ed3, 0x446356f3, 0x6ace7f3b, 0x14054ec6, 0x7a0c0a9c, 0x35b770e7, 0x19f98813, 0x4ccea734,
-0x0689da7b, 0x43ddc707, 0x39ea8fdc, 0x1d311ad9, 0x245b3011, 0x731c377c, 0x3fb245b0, 0x3bfe0047,
-0x6f7b824f, 0x7a9a56ba, 0x06c7337b, 0x752402c6, 0x6a0e8cd6, 0x2a3646b8, 0x2232dbcb, 0x58bacce9,
-0x312b27c0, 0x348eac94, 0x7f2a0793, 0x16c1d72f, 0x57da0783, 0x1bf77024, 0x4e2bf5ce, 0x6c079746,
-0x1fed0a3a, 0x1edb4ca8, 0x6fa4499f, 0x070157f9, 0x5bb60213, 0x7e754c72, 0x277e084a, 0x646784d4,
-0x4bd17891, 0x314dd4b9, 0x6754d65f, 0x31f34921, 0x5b1cc1cd, 0x7682cc86, 0x66a1c7e4, 0x79bf95bd,
-0x6cce84d3, 0x6bbdaa72, 0x4321e33c, 0x10b8bf51, 0x37c7ef99, 0x59649277, 0x2a697242, 0x4ba110f1,
-0x7b6405b8, 0x2438288e, 0x62337a9b, 0x3f3cd136, 0x68a644fd, 0x51c7ddcd, 0x6cdf1b2e, 0x35a2ae29,
-0x0b9ffe23, 0x2d0304c5, 0x47238bc6, 0x3efa78fa, 0x5318b2bd, 0x7201abba, 0x1a4113d8, 0x2ced6ef3,
-0x6d8e7768, 0x73abfab8, 0x0d7140d8, 0x2ed4f953, 0x79f44b62, 0x0219e80c, 0x0722ad1d, 0x3bb09944,
-0x7d44d7aa, 0x79db81e4, 0x3ae788c6, 0x4ae430f1, 0x45871ecf, 0x53c814ff, 0x352da8b5, 0x0287153e,
-0x5a43275d, 0x40c12a36, 0x7124fddc, 0x5c854523, 0x67ee4e6c, 0x03aabf25, 0x2d6ab386, 0x526c4a86,
-0x43c2a5f8, 0x6695634f, 0x3bcc098f, 0x2c21a9c1, 0x7199aa2a, 0x0901ca9f, 0x08cb9170, 0x14fd90f1,
-0x5876cfcd, 0x6c4748e2, 0x562ea892, 0x5ae69f42, 0x100658c2, 0x1d421d99, 0x48db5d4b, 0x08a79aac,
-0x07ddcb89, 0x18546c37, 0x5649e78b, 0x6a67147f, 0x2fd3e018, 0x70edb23e, 0x672bd731, 0x1b4d2b03,
-0x7b36d2d2, 0x396cc567, 0x5885244a, 0x2aa52cf8, 0x47bb6821, 0x4d2d9be6, 0x2e0e2784, 0x09303310,
-0x59d3ef77, 0x152ec414, 0x14234dc8, 0x5386366e, 0x0d65d415, 0x13b2b09e, 0x1fe7305c, 0x148a6c38,
-0x11c44c99, 0x7983507a, 0x1d2c8710, 0x6e7ba070, 0x0e5ab9b9, 0x43005097, 0x6a2b64b2, 0x0f1de3bb,
-0x6fe4f43e, 0x494cafbe, 0x4c5cc769, 0x201f8b6f, 0x177f0c54, 0x67692ada, 0x10030291, 0x044e6700,
-0x23756fbc, 0x4ac9820b, 0x1a033c94, 0x043a1b13, 0x1e2d39d9, 0x61cef499, 0x591a43ee, 0x70c944e2,
-0x272c5241, 0x050798f6, 0x677fee46, 0x03f3c0aa, 0x73d4256d, 0x56ad1a44, 0x45f49e91, 0x6f55955f,
-0x3ca7427b, 0x762f8e50, 0x3379c13e, 0x07dd347d, 0x7686cafb, 0x6fd6302e, 0x50b29971, 0x61b2775e,
-0x705faab0, 0x36a7a017, 0x4ce101d3, 0x6b3c077d, 0x5ebdc84c, 0x61a794a2, 0x4b5ff558, 0x3c7200c5,
-0x0cfece36, 0x51890909, 0x2e4dc125, 0x5dadd8f9, 0x68a677b8, 0x66e3bff1, 0x787840a5, 0x61f42c94,
-0x7cb2bbe7, 0x57233ff2, 0x5599bd36, 0x4821a50b, 0x7be642fd, 0x4345a74f, 0x67ca7053, 0x351500ba
-];
+						let tokens = Encoder::new(&data, start, std::slice::from_ref(&costs), params).run();
+						assert_eq!(cost_of_tokens(&tokens, &costs), cost);
+					}
+				}
+			}
+		}
+		assert!(improved);
+	}
+}
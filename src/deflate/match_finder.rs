@@ -0,0 +1,200 @@
+use super::lempel_ziv::{MAX_REP_LEN, MAX_REP_DIST};
+
+const HASH_BITS: u32 = 15;
+const TOO_FAR: usize = 4096; // a length 3 repetition this far back usually costs more than 3 literals
+const TREE_WINDOW: usize = 1 << 16; // positions kept in the tree, a power of 2 above MAX_REP_DIST
+
+fn hash(data: &[u8], pos: usize) -> usize {
+	let d = &data[pos..pos + 3];
+	(((d[0] as usize) << 10) ^ ((d[1] as usize) << 5) ^ d[2] as usize) & ((1 << HASH_BITS) - 1)
+}
+
+fn common_len(data: &[u8], a: usize, b: usize, known: usize, max_len: usize) -> usize {
+	// how many bytes data[a..] and data[b..] agree on, known of them already checked, max_len at most
+	let mut len = known;
+	while len < max_len && data[a + len] == data[b + len] {
+		len += 1;
+	}
+	len
+}
+
+pub struct HashChain<'a> {
+	// classic zlib style match finder.
+	// head holds the latest position (+1, 0 is none) of every hash of 3 bytes,
+	// prev links every position in the window to the previous one with the same hash.
+	data: &'a [u8],
+	head: Vec<u32>,
+	prev: Vec<u32>,
+}
+
+impl HashChain<'_> {
+	pub fn new(data: &[u8], start: usize) -> HashChain<'_> {
+		let mut chain = HashChain {
+			data,
+			head: vec![0; 1 << HASH_BITS],
+			prev: vec![0; MAX_REP_DIST],
+		};
+		for p in start.saturating_sub(MAX_REP_DIST)..start {
+			// remember the dictionary
+			chain.insert(p);
+		}
+		chain
+	}
+
+	pub fn insert(&mut self, pos: usize) {
+		if pos + 3 > self.data.len() {
+			return;
+		}
+		let h = hash(self.data, pos);
+		self.prev[pos % MAX_REP_DIST] = self.head[h];
+		self.head[h] = pos as u32 + 1;
+	}
+
+	pub fn longest_match(&self, pos: usize, longer_than: usize, max_chain: usize, nice_len: usize) -> (usize, usize) {
+		// returns (len, dist) of the longest repetition of the bytes at pos that is longer than longer_than,
+		// or (0, 0) if there is none.
+		let max_len = MAX_REP_LEN.min(self.data.len() - pos);
+		if max_len < 3 {
+			return (0, 0);
+		}
+		let mut best = (0, 0);
+		let mut best_len = longer_than.max(2);
+		let mut candidate = self.head[hash(self.data, pos)];
+		let mut chain_left = max_chain;
+		while candidate != 0 && chain_left > 0 && best_len < max_len {
+			let start = candidate as usize - 1;
+			if pos - start > MAX_REP_DIST {
+				break;
+			}
+			// the byte that would make this one longer than best is the most likely to differ
+			if self.data[start + best_len] == self.data[pos + best_len] {
+				let len = self.data[start..start + max_len].iter()
+					.zip(&self.data[pos..pos + max_len])
+					.take_while(|(a, b)| a == b)
+					.count();
+				if len > best_len && !(len == 3 && pos - start > TOO_FAR) {
+					best = (len, pos - start);
+					best_len = len;
+					if len >= nice_len {
+						break;
+					}
+				}
+			}
+			candidate = self.prev[start % MAX_REP_DIST];
+			chain_left -= 1;
+		}
+		best
+	}
+
+	pub fn reps(&self, pos: usize, max_depth: usize, nice_len: usize, out: &mut Vec<(usize, u32)>) {
+		// every repetition of the bytes at pos that is longer than all closer ones, as (dist, len), closest first.
+		// looks at max_depth earlier positions at most, and stops at one of nice_len.
+		let max_len = MAX_REP_LEN.min(self.data.len() - pos);
+		if max_len < 3 {
+			return;
+		}
+		let mut best_len = 2;
+		let mut candidate = self.head[hash(self.data, pos)];
+		let mut depth_left = max_depth;
+		while candidate != 0 && depth_left > 0 && best_len < max_len {
+			let start = candidate as usize - 1;
+			if pos - start > MAX_REP_DIST {
+				break;
+			}
+			if self.data[start + best_len] == self.data[pos + best_len] {
+				let len = common_len(self.data, start, pos, 0, max_len);
+				if len > best_len {
+					out.push((pos - start, len as u32));
+					best_len = len;
+					if len >= nice_len {
+						break;
+					}
+				}
+			}
+			candidate = self.prev[start % MAX_REP_DIST];
+			depth_left -= 1;
+		}
+	}
+}
+
+pub struct BinaryTree<'a> {
+	// lzma's bt match finder, for positions sharing a hash of 3 bytes.
+	// the positions of each hash form a binary search tree ordered by the data following them, newest at the root.
+	// as each position is closer than the ones under it, the path searching for the bytes at pos meets the closest
+	// repetition of every length, and inserting pos as the new root is done along the same path.
+	data: &'a [u8],
+	head: Vec<u32>, // the root of every hash (+1, 0 is none)
+	children: Vec<[u32; 2]>, // the smaller and larger subtree of every position in the window, by pos % TREE_WINDOW
+}
+
+impl BinaryTree<'_> {
+	pub fn new(data: &[u8], start: usize, max_depth: usize, nice_len: usize) -> BinaryTree<'_> {
+		let mut tree = BinaryTree {
+			data,
+			head: vec![0; 1 << HASH_BITS],
+			children: vec![[0; 2]; TREE_WINDOW],
+		};
+		for p in start.saturating_sub(MAX_REP_DIST)..start {
+			// remember the dictionary
+			tree.insert(p, max_depth, nice_len, None);
+		}
+		tree
+	}
+
+	pub fn insert(&mut self, pos: usize, max_depth: usize, nice_len: usize, mut out: Option<&mut Vec<(usize, u32)>>) {
+		// adds pos to its tree. out, if given, gets the repetitions of pos like HashChain::reps finds them.
+		// nodes deeper than max_depth are dropped, and a node that agrees with pos on nice_len bytes is replaced by it.
+		let max_len = MAX_REP_LEN.min(self.data.len() - pos);
+		if max_len < 3 {
+			return;
+		}
+		let len_limit = max_len.min(nice_len.max(3));
+		let h = hash(self.data, pos);
+		let mut candidate = self.head[h];
+		self.head[h] = pos as u32 + 1;
+
+		// where the next smaller and larger nodes found get linked, and how much those agree with pos
+		let (mut smaller, mut larger) = ((pos % TREE_WINDOW, 0), (pos % TREE_WINDOW, 1));
+		let (mut smaller_len, mut larger_len) = (0, 0);
+		let mut best_len = 2;
+		let mut depth_left = max_depth;
+		loop {
+			let start = (candidate as usize).wrapping_sub(1);
+			if candidate == 0 || pos - start > MAX_REP_DIST || depth_left == 0 {
+				self.children[smaller.0][smaller.1] = 0;
+				self.children[larger.0][larger.1] = 0;
+				return;
+			}
+			depth_left -= 1;
+			// everything under here agrees with pos on as much as the bounds do
+			let len = common_len(self.data, start, pos, smaller_len.min(larger_len), len_limit);
+			if len > best_len {
+				best_len = len;
+				if let Some(out) = out.as_mut() {
+					// past nice_len the tree can't tell, but the repetition itself may go on
+					let len = if len == len_limit {common_len(self.data, start, pos, len, max_len)} else {len};
+					out.push((pos - start, len as u32));
+				}
+			}
+			let node = start % TREE_WINDOW;
+			if len == len_limit {
+				// pos takes the place of start, which it is closer than and indistinguishable from
+				self.children[smaller.0][smaller.1] = self.children[node][0];
+				self.children[larger.0][larger.1] = self.children[node][1];
+				return;
+			}
+			if self.data[start + len] < self.data[pos + len] {
+				// start and its smaller subtree are smaller than pos, go on in its larger one
+				self.children[smaller.0][smaller.1] = candidate;
+				smaller = (node, 1);
+				smaller_len = len;
+				candidate = self.children[node][1];
+			} else {
+				self.children[larger.0][larger.1] = candidate;
+				larger = (node, 0);
+				larger_len = len;
+				candidate = self.children[node][0];
+			}
+		}
+	}
+}
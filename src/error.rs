@@ -0,0 +1,51 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum GuyzipError {
+	// more kinds of failure may be added, so matches need a catch-all arm
+	Io(io::Error), // reading input or writing output failed
+	InvalidData(String), // the compressed data is corrupt, or not in the expected format
+	UnexpectedEof, // the compressed data ends too early
+	InvalidHeader(&'static str), // header fields that can't be written
+}
+
+impl fmt::Display for GuyzipError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			GuyzipError::Io(e) => e.fmt(f),
+			GuyzipError::InvalidData(msg) => f.write_str(msg),
+			GuyzipError::UnexpectedEof => f.write_str("unexpected end of file"),
+			GuyzipError::InvalidHeader(msg) => f.write_str(msg),
+		}
+	}
+}
+
+impl Error for GuyzipError {
+	fn source(&self) -> Option<&(dyn Error + 'static)> {
+		match self {
+			GuyzipError::Io(e) => Some(e),
+			_ => None,
+		}
+	}
+}
+
+impl From<io::Error> for GuyzipError {
+	fn from(e: io::Error) -> GuyzipError {
+		GuyzipError::Io(e)
+	}
+}
+
+impl From<GuyzipError> for io::Error {
+	// for the Write impls of the encoders
+	fn from(e: GuyzipError) -> io::Error {
+		match e {
+			GuyzipError::Io(e) => e,
+			GuyzipError::UnexpectedEof => io::Error::new(io::ErrorKind::UnexpectedEof, e),
+			GuyzipError::InvalidData(_) => io::Error::new(io::ErrorKind::InvalidData, e),
+			GuyzipError::InvalidHeader(_) => io::Error::new(io::ErrorKind::InvalidInput, e),
+		}
+	}
+}
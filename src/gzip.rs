@@ -0,0 +1,300 @@
+use std::convert::TryFrom;
+use std::io::{self, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::crc32;
+use crate::deflate::DeflateEncoder;
+use crate::error::GuyzipError;
+use crate::options::CompressionOptions;
+use crate::inflate::{self, invalid_data};
+
+// header flags
+const FHCRC: u8 = 1 << 1;
+const FEXTRA: u8 = 1 << 2;
+const FNAME: u8 = 1 << 3;
+const FCOMMENT: u8 = 1 << 4;
+const FRESERVED: u8 = 0xE0;
+
+#[derive(Clone, Debug)]
+pub struct GzHeader {
+	// the optional parts of a gzip member header, built like GzHeader::new().file_name("a.txt").mtime(1700000000)
+	file_name: Option<Vec<u8>>, // latin-1, without the terminating zero
+	comment: Option<Vec<u8>>, // same
+	mtime: u32, // seconds since the unix epoch, 0 is none
+	extra: Option<Vec<u8>>, // the FEXTRA subfields, each an id, a little endian u16 length and the data
+	header_crc: bool,
+	os: u8,
+}
+
+impl GzHeader {
+	pub fn new() -> GzHeader {
+		GzHeader {
+			file_name: None,
+			comment: None,
+			mtime: 0,
+			extra: None,
+			header_crc: false,
+			os: 0xFF, // unknown
+		}
+	}
+
+	pub fn file_name(mut self, name: &str) -> GzHeader {
+		// the name of the original file, without its directory.
+		self.file_name = Some(latin1(name));
+		self
+	}
+
+	pub fn comment(mut self, comment: &str) -> GzHeader {
+		self.comment = Some(latin1(comment));
+		self
+	}
+
+	pub fn mtime(mut self, mtime: u32) -> GzHeader {
+		self.mtime = mtime;
+		self
+	}
+
+	pub fn modified(self, time: SystemTime) -> GzHeader {
+		// the modification time of the original file. times that don't fit are left out (0).
+		// if SOURCE_DATE_EPOCH is set, later times are clamped to it, for reproducible builds.
+		let seconds = time.duration_since(UNIX_EPOCH).map_or(0, |t| t.as_secs());
+		let seconds = match source_date_epoch() {
+			Some(epoch) => seconds.min(epoch),
+			None => seconds,
+		};
+		self.mtime(u32::try_from(seconds).unwrap_or(0))
+	}
+
+	pub fn extra_subfield(mut self, id: [u8; 2], data: &[u8]) -> GzHeader {
+		// adds an FEXTRA subfield. all of them together must fit in 65535 bytes, GzEncoder fails otherwise.
+		let extra = self.extra.get_or_insert_with(Vec::new);
+		extra.extend_from_slice(&id);
+		extra.extend_from_slice(&(data.len() as u16).to_le_bytes()); // a longer one fails the total length check anyway
+		extra.extend_from_slice(data);
+		self
+	}
+
+	pub fn header_crc(mut self, header_crc: bool) -> GzHeader {
+		// whether to protect the header with a crc16
+		self.header_crc = header_crc;
+		self
+	}
+
+	pub fn os(mut self, os: u8) -> GzHeader {
+		// the file system the file came from, as numbered by RFC 1952 (3 is unix)
+		self.os = os;
+		self
+	}
+
+	pub fn get_file_name(&self) -> Option<&[u8]> {
+		// latin-1, as stored
+		self.file_name.as_deref()
+	}
+
+	pub fn get_comment(&self) -> Option<&[u8]> {
+		self.comment.as_deref()
+	}
+
+	pub fn get_mtime(&self) -> u32 {
+		self.mtime
+	}
+
+	fn to_bytes(&self, options: &CompressionOptions) -> Result<Vec<u8>, GuyzipError> {
+		let mut flags = 0;
+		let mut header = vec![
+			0x1F, 0x8B, // magic
+			0x08, // Compression Method = DEFLATE
+			0x00, // Flags, set below
+		];
+		header.extend_from_slice(&self.mtime.to_le_bytes()); // Modification Time
+		// Extra Flags, like gzip: 2 for the best compression and 4 for the fastest
+		header.push(match options.level.get() {
+			9 => 2,
+			1 => 4,
+			_ => 0,
+		});
+		header.push(self.os); // OS
+
+		if let Some(extra) = &self.extra {
+			let extra_len = u16::try_from(extra.len())
+				.map_err(|_| GuyzipError::InvalidHeader("gzip extra field too long"))?;
+			flags |= FEXTRA;
+			header.extend_from_slice(&extra_len.to_le_bytes());
+			header.extend_from_slice(extra);
+		}
+		for (flag, field) in [(FNAME, &self.file_name), (FCOMMENT, &self.comment)] {
+			if let Some(field) = field {
+				flags |= flag;
+				header.extend_from_slice(field);
+				header.push(0);
+			}
+		}
+		if self.header_crc {
+			flags |= FHCRC;
+		}
+		header[3] = flags;
+		if self.header_crc {
+			let crc16 = crc32::crc32(&header) as u16; // the two least significant bytes of the header's crc32
+			header.extend_from_slice(&crc16.to_le_bytes());
+		}
+		Ok(header)
+	}
+}
+
+impl Default for GzHeader {
+	fn default() -> GzHeader {
+		GzHeader::new()
+	}
+}
+
+fn latin1(s: &str) -> Vec<u8> {
+	// characters latin-1 doesn't have become '?', as do zeros, which would end the field
+	s.chars().map(|c| match c as u32 {
+		1..=0xFF => c as u8,
+		_ => b'?',
+	}).collect()
+}
+
+fn source_date_epoch() -> Option<u64> {
+	std::env::var("SOURCE_DATE_EPOCH").ok()?.trim().parse().ok()
+}
+
+pub struct GzEncoder<W: Write> {
+	// writes a single gzip member, compressing data as it is written.
+	deflate_encoder: DeflateEncoder<W>, // also computes the crc32 and size
+}
+
+impl<W: Write> GzEncoder<W> {
+	pub fn new(out: W) -> Result<GzEncoder<W>, GuyzipError> {
+		GzEncoder::with_options(out, CompressionOptions::default())
+	}
+
+	pub fn with_options(out: W, options: CompressionOptions) -> Result<GzEncoder<W>, GuyzipError> {
+		GzEncoder::with_header(out, options, &GzHeader::new())
+	}
+
+	pub fn with_header(mut out: W, options: CompressionOptions, header: &GzHeader) -> Result<GzEncoder<W>, GuyzipError> {
+		out.write_all(&header.to_bytes(&options)?)?;
+
+		Ok(GzEncoder {
+			deflate_encoder: DeflateEncoder::with_options(out, options),
+		})
+	}
+
+	pub fn finish(self) -> Result<W, GuyzipError> {
+		let (mut out, crc32) = self.deflate_encoder.finish_with_crc32()?;
+		out.write_all(&crc32.finalize().to_le_bytes())?; // CRC32
+		out.write_all(&(crc32.amount() as u32).to_le_bytes())?; // size modulo 2^32
+		Ok(out)
+	}
+}
+
+impl<W: Write> Write for GzEncoder<W> {
+	fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+		self.deflate_encoder.write(data)
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		self.deflate_encoder.flush()
+	}
+}
+
+pub fn read_header(file: &[u8]) -> Result<GzHeader, GuyzipError> {
+	// the header of the first member of a gzip file
+	parse_gzip_header(file).map(|(_len, header)| header)
+}
+
+pub fn decompress(file: &[u8]) -> Result<Vec<u8>, GuyzipError> {
+	// a gzip file is one or more members, each decompressing to its own part of the output.
+	let mut out = vec![];
+	let mut rest = file;
+	while !rest.is_empty() {
+		let (header_len, _header) = parse_gzip_header(rest)?;
+		rest = &rest[header_len..];
+
+		let member_start = out.len();
+		let deflate_len = inflate::inflate(rest, &mut out)?;
+		rest = &rest[deflate_len..];
+
+		if rest.len() < 8 {
+			return Err(GuyzipError::UnexpectedEof);
+		}
+		let expected_crc32 = u32::from_le_bytes([rest[0], rest[1], rest[2], rest[3]]);
+		let expected_size = u32::from_le_bytes([rest[4], rest[5], rest[6], rest[7]]);
+		rest = &rest[8..];
+
+		let member = &out[member_start..];
+		let crc32 = crc32::crc32(member);
+		if crc32 != expected_crc32 {
+			return Err(invalid_data(&format!("crc32 mismatch: stored {:08x}, computed {:08x}", expected_crc32, crc32)));
+		}
+		let size = member.len() as u32; // size modulo 2^32
+		if size != expected_size {
+			return Err(invalid_data(&format!("length mismatch: stored {}, computed {}", expected_size, size)));
+		}
+	}
+	Ok(out)
+}
+
+fn parse_gzip_header(file: &[u8]) -> Result<(usize, GzHeader), GuyzipError> {
+	// validates the header of a gzip member, returns its length and the fields in it.
+	if !file.starts_with(&[0x1F, 0x8B]) {
+		return Err(invalid_data("not in gzip format"));
+	}
+	if file.len() < 10 {
+		return Err(GuyzipError::UnexpectedEof);
+	}
+	if file[2] != 0x08 {
+		return Err(invalid_data(&format!("unknown compression method {}", file[2])));
+	}
+	let flags = file[3];
+	if flags & FRESERVED != 0 {
+		return Err(invalid_data(&format!("unknown flags 0x{:02x}", flags)));
+	}
+	let mut header = GzHeader::new()
+		.mtime(u32::from_le_bytes([file[4], file[5], file[6], file[7]]))
+		.os(file[9]);
+	// Extra Flags are informative only
+	let mut len = 10;
+
+	if flags & FEXTRA != 0 {
+		if file.len() < len + 2 {
+			return Err(GuyzipError::UnexpectedEof);
+		}
+		let extra_len = u16::from_le_bytes([file[len], file[len + 1]]) as usize;
+		len += 2;
+		match file.get(len..len + extra_len) {
+			Some(extra) => header.extra = Some(extra.to_vec()),
+			None => return Err(GuyzipError::UnexpectedEof),
+		}
+		len += extra_len;
+	}
+	for flag in &[FNAME, FCOMMENT] {
+		if flags & flag != 0 {
+			// zero terminated
+			let field = match file.get(len..).and_then(|rest| rest.iter().position(|b| *b == 0)) {
+				Some(terminator) => file[len..len + terminator].to_vec(),
+				None => return Err(GuyzipError::UnexpectedEof),
+			};
+			len += field.len() + 1;
+			if *flag == FNAME {
+				header.file_name = Some(field);
+			} else {
+				header.comment = Some(field);
+			}
+		}
+	}
+	if flags & FHCRC != 0 {
+		if file.len() < len + 2 {
+			return Err(GuyzipError::UnexpectedEof);
+		}
+		let expected_crc16 = u16::from_le_bytes([file[len], file[len + 1]]);
+		let crc16 = crc32::crc32(&file[..len]) as u16; // the two least significant bytes of the header's crc32
+		if crc16 != expected_crc16 {
+			return Err(invalid_data("header crc mismatch"));
+		}
+		header.header_crc = true;
+		len += 2;
+	}
+	Ok((len, header))
+}
@@ -1,5 +1,3 @@
-use std::collections::VecDeque;
-
 #[derive(Copy, Clone)]
 pub struct HuffmanCode {
 	pub code: u32,
@@ -45,7 +43,7 @@ pub fn calc_codes(lengths: &[u8]) -> Tree {
 	}
 	
 	// reverse the codes
-	for mut huffman_code in &mut codes {
+	for huffman_code in &mut codes {
 		let mut new_code = 0;
 		for _ in 0..huffman_code.length {
 			new_code <<= 1;
@@ -58,74 +56,229 @@ pub fn calc_codes(lengths: &[u8]) -> Tree {
 	codes
 }
 
+const MAX_SYMBOLS: usize = 288; // the largest alphabet of deflate
+const MAX_LIST_LEN: usize = 2 * MAX_SYMBOLS - 2;
+
 pub fn gen_lengths(weights: &[u64], l: u8, out: &mut[u8]) {
 	assert_eq!(weights.len(), out.len());
+	assert!((2..=MAX_SYMBOLS).contains(&weights.len()) && (1..=15).contains(&l));
 	out.iter_mut().for_each(|x| *x = 0);
 
 	// finds optimal huffman tree with length bound l (including) and given weights. stores code lens in out.
-	// based on the algorith presented in https://www.ics.uci.edu/~dan/pubs/LenLimHuff.pdf
-	#[derive(Clone)]
-	struct Package {
-		weight: u64,
-		contents: Vec<usize>,
+	// package-merge, as in https://www.ics.uci.edu/~dan/pubs/LenLimHuff.pdf, without allocating:
+	// the list of every level (deepest first) is the used symbols merged with the pairs of the deeper list.
+	// at most 2n - 2 items of any list get chosen, so only that many are kept, together with which of them are symbols.
+	// the chosen symbols of a level are then always the lightest ones, so their count is all that's needed.
+	let mut symbols = [0usize; MAX_SYMBOLS]; // the used ones, lightest first
+	let mut n = 0;
+	for (i, w) in weights.iter().enumerate() {
+		if *w != 0 {
+			symbols[n] = i;
+			n += 1;
+		}
+	}
+	if n < 2 {
+		// a single code would get length 0, which can't be written. like zlib, use two codes of length 1,
+		// the used symbol (if any) and symbol 0 or 1, so the tree is complete and every inflater takes it.
+		let used = if n == 1 {Some(symbols[0])} else {None};
+		let other = if used == Some(0) {1} else {0};
+		out[other] = 1;
+		out[used.unwrap_or(1)] = 1;
+		return;
+	}
+	assert!(n <= 1 << l, "too many symbols for the length bound");
+	let symbols = &mut symbols[..n];
+	symbols.sort_unstable_by_key(|i| (weights[*i], std::cmp::Reverse(*i))); // ties as in the first version, for the same output
+	let max_len = 2 * n - 2;
+
+	let mut list = [0u64; MAX_LIST_LEN]; // weights of the current level's list
+	let mut list_len = 0;
+	let mut next_list = [0u64; MAX_LIST_LEN];
+	let mut is_symbol = [[0u64; MAX_LIST_LEN.div_ceil(64)]; 15]; // bit sets, per level from the shallowest
+	for level in (0..l as usize).rev() {
+		// merge the symbols with the packages (pairs) of the deeper list. on equal weights symbols come first.
+		let package_count = list_len / 2;
+		let (mut s, mut p, mut len) = (0, 0, 0);
+		while len < max_len && (s < n || p < package_count) {
+			let package_weight = if p < package_count {list[2 * p] + list[2 * p + 1]} else {u64::MAX};
+			if s < n && weights[symbols[s]] <= package_weight {
+				next_list[len] = weights[symbols[s]];
+				is_symbol[level][len / 64] |= 1 << (len % 64);
+				s += 1;
+			} else {
+				next_list[len] = package_weight;
+				p += 1;
+			}
+			len += 1;
+		}
+		std::mem::swap(&mut list, &mut next_list);
+		list_len = len;
 	}
-	let mut levels_left = l;
-	let mut curr_packages: VecDeque<Package> = VecDeque::new();
-	let mut new_level: Vec<Package> = weights.iter()
-		.enumerate()
-		.filter(|(_i, w)| **w != 0)
-		.map(|(i, w)|
-			Package {
-				weight: *w,
-				contents: vec![i],
-			})
-		.collect();
-	new_level.sort_by_key(|p| std::cmp::Reverse(p.weight));
-	let new_level = VecDeque::from(new_level);
-	let mut x = (new_level.len() - 1) << l;
-	while x > 0 {
-		if levels_left > 0 {
-			curr_packages = merge(curr_packages, new_level.clone());
-			levels_left -= 1;
+
+	// choose the first 2n - 2 items of the shallowest list, and go deeper through the packages chosen
+	let mut chosen = max_len;
+	for level_is_symbol in &is_symbol[..l as usize] {
+		let mut chosen_symbols = 0;
+		for i in 0..chosen {
+			chosen_symbols += (level_is_symbol[i / 64] >> (i % 64)) as usize & 1;
 		}
-		if x & 1 == 1 {
-			curr_packages.pop_back().unwrap().contents.iter().for_each(|i| out[*i] += 1);
+		// every level a symbol is chosen in makes its code a bit longer
+		for i in &symbols[..chosen_symbols] {
+			out[*i] += 1;
 		}
-		curr_packages = package(curr_packages);
-		x >>= 1;
+		chosen = 2 * (chosen - chosen_symbols);
 	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::collections::VecDeque;
 
-	fn merge(mut a: VecDeque<Package>, mut b: VecDeque<Package>) -> VecDeque<Package> {
-		let mut res = VecDeque::new();
-		res.reserve(a.len() + b.len());
-		while a.len() > 0 && b.len() > 0 {
-			if a.back().unwrap().weight < b.back().unwrap().weight {
+	// the straightforward package-merge gen_lengths replaced, building the package lists as they are described
+	fn reference_gen_lengths(weights: &[u64], l: u8, out: &mut[u8]) {
+		assert_eq!(weights.len(), out.len());
+		assert!(weights.len() >= 2 && l >= 1);
+		out.iter_mut().for_each(|x| *x = 0);
+
+		// finds optimal huffman tree with length bound l (including) and given weights. stores code lens in out.
+		// based on the algorith presented in https://www.ics.uci.edu/~dan/pubs/LenLimHuff.pdf
+		#[derive(Clone)]
+		struct Package {
+			weight: u64,
+			contents: Vec<usize>,
+		}
+		let mut levels_left = l;
+		let mut curr_packages: VecDeque<Package> = VecDeque::new();
+		let mut new_level: Vec<Package> = weights.iter()
+			.enumerate()
+			.filter(|(_i, w)| **w != 0)
+			.map(|(i, w)|
+				Package {
+					weight: *w,
+					contents: vec![i],
+				})
+			.collect();
+		if new_level.len() < 2 {
+			// a single code would get length 0, which can't be written. like zlib, use two codes of length 1,
+			// the used symbol (if any) and symbol 0 or 1, so the tree is complete and every inflater takes it.
+			let used = new_level.first().map(|p| p.contents[0]);
+			let other = if used == Some(0) {1} else {0};
+			out[other] = 1;
+			out[used.unwrap_or(1)] = 1;
+			return;
+		}
+		new_level.sort_by_key(|p| std::cmp::Reverse(p.weight));
+		let new_level = VecDeque::from(new_level);
+		let mut x = (new_level.len() - 1) << l;
+		while x > 0 {
+			if levels_left > 0 {
+				curr_packages = merge(curr_packages, new_level.clone());
+				levels_left -= 1;
+			}
+			if x & 1 == 1 {
+				curr_packages.pop_back().unwrap().contents.iter().for_each(|i| out[*i] += 1);
+			}
+			curr_packages = package(curr_packages);
+			x >>= 1;
+		}
+
+		fn merge(mut a: VecDeque<Package>, mut b: VecDeque<Package>) -> VecDeque<Package> {
+			let mut res = VecDeque::new();
+			res.reserve(a.len() + b.len());
+			while !a.is_empty() && !b.is_empty() {
+				if a.back().unwrap().weight < b.back().unwrap().weight {
+					res.push_front(a.pop_back().unwrap());
+				} else {
+					res.push_front(b.pop_back().unwrap());
+				}
+			}
+			while !a.is_empty() {
 				res.push_front(a.pop_back().unwrap());
-			} else {
+			}
+			while !b.is_empty() {
 				res.push_front(b.pop_back().unwrap());
 			}
+			res
 		}
-		while a.len() > 0 {
-			res.push_front(a.pop_back().unwrap());
+
+		fn package(mut v: VecDeque<Package>) -> VecDeque<Package> {
+			let mut res = VecDeque::new();
+			res.reserve(v.len() / 2);
+			for _ in 0..v.len() / 2 {
+				let mut p0 = v.pop_back().unwrap();
+				let mut p1 = v.pop_back().unwrap();
+				p0.contents.append(&mut p1.contents);
+				res.push_front(Package {
+					weight: p0.weight + p1.weight,
+					contents: p0.contents,
+				})
+			}
+			res
 		}
-		while b.len() > 0 {
-			res.push_front(b.pop_back().unwrap());
+	}
+
+	struct Rng(u64);
+
+	impl Rng {
+		fn next(&mut self) -> u64 {
+			// xorshift, so the cases are the same every run
+			self.0 ^= self.0 << 13;
+			self.0 ^= self.0 >> 7;
+			self.0 ^= self.0 << 17;
+			self.0
 		}
-		res
 	}
 
-	fn package(mut v: VecDeque<Package>) -> VecDeque<Package> {
-		let mut res = VecDeque::new();
-		res.reserve(v.len() / 2);
-		for _ in 0..v.len() / 2 {
-			let mut p0 = v.pop_back().unwrap();
-			let mut p1 = v.pop_back().unwrap();
-			p0.contents.append(&mut p1.contents);
-			res.push_front(Package {
-				weight: p0.weight + p1.weight,
-				contents: p0.contents,
-			})
+	fn random_weights(rng: &mut Rng, n: usize) -> Vec<u64> {
+		// some unused symbols, and sometimes weights spread over many orders of magnitude, which hit the length limit
+		let skewed = rng.next() & 1 == 0;
+		let zeros = rng.next() % 4;
+		(0..n).map(|_| match rng.next() % 8 {
+			z if z < zeros => 0,
+			_ if skewed => 1 << (rng.next() % 40),
+			_ => 1 + rng.next() % 1000,
+		}).collect()
+	}
+
+	#[test]
+	fn fewer_than_two_used_symbols() {
+		// a single code would get length 0, which inflaters reject. these get exactly two codes of length 1.
+		let mut lengths = [9; 30];
+		gen_lengths(&[0; 30], 15, &mut lengths);
+		assert_eq!(lengths.iter().filter(|&&len| len != 0).count(), 2);
+		assert_eq!((lengths[0], lengths[1]), (1, 1));
+
+		for used in [0, 1, 7, 29] {
+			let mut weights = [0; 30];
+			weights[used] = 1000;
+			gen_lengths(&weights, 15, &mut lengths);
+			assert_eq!(lengths[used], 1);
+			assert_eq!(lengths.iter().filter(|&&len| len != 0).count(), 2);
+			assert!(lengths.iter().all(|&len| len <= 1));
+		}
+	}
+
+	#[test]
+	fn same_lengths_as_reference() {
+		let mut rng = Rng(0x2545F4914F6CDD1D);
+		for &n in &[19, 30, 286] {
+			for &l in &[7, 15] {
+				for _ in 0..300 {
+					let mut weights = random_weights(&mut rng, n);
+					// no more used symbols than codes of length l, so one exists
+					weights.iter_mut().filter(|w| **w != 0).skip(1 << l).for_each(|w| *w = 0);
+					let mut lengths = vec![0; n];
+					let mut expected = vec![0; n];
+					gen_lengths(&weights, l, &mut lengths);
+					reference_gen_lengths(&weights, l, &mut expected);
+					assert_eq!(lengths, expected, "weights {:?} limit {}", weights, l);
+
+					// a complete code within the limit
+					assert!(lengths.iter().all(|&len| len <= l));
+					assert_eq!(lengths.iter().filter(|&&len| len > 0).map(|&len| 1u64 << (15 - len)).sum::<u64>(), 1 << 15);
+				}
+			}
 		}
-		res
 	}
 }
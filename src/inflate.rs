@@ -0,0 +1,399 @@
+use crate::error::GuyzipError;
+use crate::huffman;
+use crate::deflate::{LEN_TO_CODE, DIST_TO_CODE, CODE_LEN_OF_CODE_ORDER};
+
+pub fn inflate(data: &[u8], out: &mut Vec<u8>) -> Result<usize, GuyzipError> {
+	// decodes a single deflate stream found at the start of data, appending the decoded bytes to out.
+	// returns how many bytes of data the stream took up (including the partially used last byte).
+	let stream_start = out.len();
+	let mut reader = BitReader::new(data);
+
+	loop {
+		let is_final = reader.read_bits(1)? == 1;
+		match reader.read_bits(2)? {
+			0 => inflate_stored_block(&mut reader, out)?,
+			1 => {
+				let literal_decoder = Decoder::new(&huffman::LITERAL_FIXED_CODES)?;
+				let distance_decoder = Decoder::new(&huffman::DISTANCE_FIXED_CODES)?;
+				inflate_codes_block(&mut reader, out, stream_start, &literal_decoder, &distance_decoder)?;
+			}
+			2 => {
+				let (literal_decoder, distance_decoder) = read_dynamic_block_header(&mut reader)?;
+				inflate_codes_block(&mut reader, out, stream_start, &literal_decoder, &distance_decoder)?;
+			}
+			_ => return Err(invalid_data("invalid block type")),
+		}
+		if is_final {
+			break;
+		}
+	}
+
+	Ok(reader.bytes_consumed())
+}
+
+fn inflate_stored_block(reader: &mut BitReader, out: &mut Vec<u8>) -> Result<(), GuyzipError> {
+	reader.align_to_byte();
+	let len = reader.read_bits(16)?;
+	let nlen = reader.read_bits(16)?;
+	if len != !nlen & 0xFFFF {
+		return Err(invalid_data("stored block length does not match its complement"));
+	}
+	out.extend_from_slice(reader.read_aligned_bytes(len as usize)?);
+	Ok(())
+}
+
+fn inflate_codes_block(reader: &mut BitReader, out: &mut Vec<u8>, stream_start: usize, literal_decoder: &Decoder, distance_decoder: &Decoder) -> Result<(), GuyzipError> {
+	loop {
+		let symbol = literal_decoder.decode(reader)? as usize;
+		match symbol {
+			0..=255 => out.push(symbol as u8),
+			256 => return Ok(()), // end of block
+			257..=285 => {
+				let (len_start, _len_end, extra_bits, _code) = LEN_TO_CODE[symbol - 257];
+				let len = (len_start + reader.read_bits(extra_bits)?) as usize;
+
+				let dist_code = distance_decoder.decode(reader)? as usize;
+				if dist_code >= DIST_TO_CODE.len() {
+					return Err(invalid_data("invalid distance code"));
+				}
+				let (dist_start, _dist_end, extra_bits, _code) = DIST_TO_CODE[dist_code];
+				let dist = (dist_start + reader.read_bits(extra_bits)?) as usize;
+				if dist > out.len() - stream_start {
+					return Err(invalid_data("distance too far back"));
+				}
+
+				// byte by byte, since the repetition may overlap itself
+				let from = out.len() - dist;
+				for i in 0..len {
+					let b = out[from + i];
+					out.push(b);
+				}
+			}
+			_ => return Err(invalid_data("invalid literal/length code")),
+		}
+	}
+}
+
+fn read_dynamic_block_header(reader: &mut BitReader) -> Result<(Decoder, Decoder), GuyzipError> {
+	let literal_count = reader.read_bits(5)? as usize + 257; // HLIT
+	let distance_count = reader.read_bits(5)? as usize + 1; // HDIST
+	let code_len_code_count = reader.read_bits(4)? as usize + 4; // HCLEN
+	if literal_count > 286 || distance_count > 30 {
+		return Err(invalid_data("too many length or distance codes"));
+	}
+
+	let mut code_len_of_code: [u8; 19] = [0; 19];
+	for i in CODE_LEN_OF_CODE_ORDER.iter().take(code_len_code_count) {
+		code_len_of_code[*i] = reader.read_bits(3)? as u8;
+	}
+	let code_len_decoder = Decoder::new(&code_len_of_code)?;
+
+	// the literal and distance code lens are encoded as one sequence, runs may cross between them.
+	let mut code_lens: Vec<u8> = Vec::with_capacity(literal_count + distance_count);
+	while code_lens.len() < literal_count + distance_count {
+		let (val, run) = match code_len_decoder.decode(reader)? {
+			val @ 0..=15 => (val as u8, 1),
+			16 => {
+				let prev = match code_lens.last() {
+					Some(prev) => *prev,
+					None => return Err(invalid_data("repeat of code length with no previous length")),
+				};
+				(prev, 3 + reader.read_bits(2)? as usize)
+			}
+			17 => (0, 3 + reader.read_bits(3)? as usize),
+			_ => (0, 11 + reader.read_bits(7)? as usize), // 18, the decoder has no other symbols
+		};
+		if code_lens.len() + run > literal_count + distance_count {
+			return Err(invalid_data("code length run is too long"));
+		}
+		code_lens.extend(std::iter::repeat_n(val, run));
+	}
+
+	if code_lens[256] == 0 {
+		return Err(invalid_data("missing end of block code"));
+	}
+	let literal_decoder = Decoder::new(&code_lens[..literal_count])?;
+	let distance_decoder = Decoder::new(&code_lens[literal_count..])?;
+	Ok((literal_decoder, distance_decoder))
+}
+
+struct Decoder {
+	// lookup table indexed by the next max_len bits of input (LSB first).
+	// holds (symbol, code length). a code length of 0 marks bits that don't start with any code.
+	table: Vec<(u16, u8)>,
+	max_len: u8,
+}
+
+impl Decoder {
+	fn new(code_lens: &[u8]) -> Result<Decoder, GuyzipError> {
+		// incomplete codes are accepted (e.g. a single distance code), only over-subscribed ones are rejected.
+		let mut kraft_sum: u32 = 0;
+		for l in code_lens {
+			if *l > 0 {
+				kraft_sum += 1 << (15 - l);
+			}
+		}
+		if kraft_sum > 1 << 15 {
+			return Err(invalid_data("over-subscribed huffman code"));
+		}
+
+		let max_len = code_lens.iter().copied().max().unwrap_or(0);
+		let mut table = vec![(0, 0); 1 << max_len];
+		for (symbol, huffman_code) in huffman::calc_codes(code_lens).iter().enumerate() {
+			if huffman_code.length == 0 {
+				continue;
+			}
+			// every index whose low bits are the code decodes to this symbol
+			let mut i = huffman_code.code as usize;
+			while i < table.len() {
+				table[i] = (symbol as u16, huffman_code.length);
+				i += 1 << huffman_code.length;
+			}
+		}
+		Ok(Decoder { table, max_len })
+	}
+
+	fn decode(&self, reader: &mut BitReader) -> Result<u16, GuyzipError> {
+		let (symbol, length) = self.table[reader.peek_bits(self.max_len) as usize];
+		if length == 0 {
+			return Err(invalid_data("invalid huffman code"));
+		}
+		reader.consume(length)?;
+		Ok(symbol)
+	}
+}
+
+struct BitReader<'a> {
+	data: &'a [u8],
+	pos: usize, // next byte to load into bit_buf
+	bit_buf: u64, // bits are consumed from the LSB
+	bit_count: u8,
+}
+
+impl<'a> BitReader<'a> {
+	fn new(data: &'a [u8]) -> BitReader<'a> {
+		BitReader {
+			data,
+			pos: 0,
+			bit_buf: 0,
+			bit_count: 0,
+		}
+	}
+
+	fn peek_bits(&mut self, len: u8) -> u32 {
+		// 32 bit max. bits after the end of data read as zeros, consume() catches their usage.
+		while self.bit_count <= 56 && self.pos < self.data.len() {
+			self.bit_buf |= (self.data[self.pos] as u64) << self.bit_count;
+			self.pos += 1;
+			self.bit_count += 8;
+		}
+		(self.bit_buf & ((1u64 << len) - 1)) as u32
+	}
+
+	fn consume(&mut self, len: u8) -> Result<(), GuyzipError> {
+		if len > self.bit_count {
+			return Err(GuyzipError::UnexpectedEof);
+		}
+		self.bit_buf >>= len;
+		self.bit_count -= len;
+		Ok(())
+	}
+
+	fn read_bits(&mut self, len: u8) -> Result<u32, GuyzipError> {
+		let bits = self.peek_bits(len);
+		self.consume(len)?;
+		Ok(bits)
+	}
+
+	fn align_to_byte(&mut self) {
+		let partial_bits = self.bit_count % 8;
+		self.bit_buf >>= partial_bits;
+		self.bit_count -= partial_bits;
+	}
+
+	fn read_aligned_bytes(&mut self, len: usize) -> Result<&'a [u8], GuyzipError> {
+		// must be byte aligned. gives back the whole bytes loaded into bit_buf and reads straight from data.
+		self.pos -= (self.bit_count / 8) as usize;
+		self.bit_buf = 0;
+		self.bit_count = 0;
+		if self.pos + len > self.data.len() {
+			return Err(GuyzipError::UnexpectedEof);
+		}
+		let bytes = &self.data[self.pos..self.pos + len];
+		self.pos += len;
+		Ok(bytes)
+	}
+
+	fn bytes_consumed(&self) -> usize {
+		self.pos - (self.bit_count / 8) as usize
+	}
+}
+
+pub(crate) fn invalid_data(msg: &str) -> GuyzipError {
+	GuyzipError::InvalidData(msg.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{deflate_raw_with_options, CompressionOptions, Level};
+
+	struct BitWriter {
+		// LSB first, as deflate packs bits
+		bytes: Vec<u8>,
+		bit_count: usize,
+	}
+
+	impl BitWriter {
+		fn new() -> BitWriter {
+			BitWriter { bytes: vec![], bit_count: 0 }
+		}
+
+		fn write_bits(&mut self, bits: u32, len: u8) {
+			for i in 0..len {
+				if self.bit_count.is_multiple_of(8) {
+					self.bytes.push(0);
+				}
+				*self.bytes.last_mut().unwrap() |= (((bits >> i) & 1) as u8) << (self.bit_count % 8);
+				self.bit_count += 1;
+			}
+		}
+
+		fn write_code(&mut self, tree: &huffman::Tree, symbol: usize) {
+			self.write_bits(tree[symbol].code, tree[symbol].length);
+		}
+	}
+
+	fn inflate_all(data: &[u8]) -> Result<Vec<u8>, GuyzipError> {
+		let mut out = vec![];
+		inflate(data, &mut out)?;
+		Ok(out)
+	}
+
+	fn compress(data: &[u8], level: u8) -> Vec<u8> {
+		deflate_raw_with_options(data, CompressionOptions::default().level(Level::new(level).unwrap())).unwrap()
+	}
+
+	fn text(len: usize) -> Vec<u8> {
+		let words = ["the ", "quick ", "brown ", "fox ", "jumps ", "over ", "lazy ", "dog ", "and ", "runs "];
+		let mut x: u32 = 12345;
+		let mut out = vec![];
+		while out.len() < len {
+			x = x.wrapping_mul(1103515245).wrapping_add(12345);
+			out.extend_from_slice(words[(x >> 16) as usize % words.len()].as_bytes());
+		}
+		out.truncate(len);
+		out
+	}
+
+	#[test]
+	fn stored_block() {
+		let data = b"stored as is";
+		let mut stream = vec![0x01, data.len() as u8, 0, !data.len() as u8, 0xFF]; // final stored block, LEN and NLEN
+		stream.extend_from_slice(data);
+		assert_eq!(inflate_all(&stream).unwrap(), data);
+		assert_eq!(inflate_all(&compress(&text(100000), 0)).unwrap(), text(100000));
+	}
+
+	#[test]
+	fn fixed_codes_block() {
+		let data = b"abcabcabc";
+		let stream = compress(data, 9);
+		assert_eq!(stream[0] >> 1 & 3, 1);
+		assert_eq!(inflate_all(&stream).unwrap(), data);
+	}
+
+	#[test]
+	fn dynamic_codes_block() {
+		let data = text(50000);
+		for level in 1..=9 {
+			let stream = compress(&data, level);
+			assert_eq!(stream[0] >> 1 & 3, 2);
+			assert_eq!(inflate_all(&stream).unwrap(), data);
+		}
+	}
+
+	#[test]
+	fn overlapping_repetition() {
+		// "a" then 5 bytes from 1 back
+		let literal_tree = huffman::calc_codes(&huffman::LITERAL_FIXED_CODES);
+		let distance_tree = huffman::calc_codes(&huffman::DISTANCE_FIXED_CODES);
+		let mut w = BitWriter::new();
+		w.write_bits(1, 1);
+		w.write_bits(1, 2); // fixed codes
+		w.write_code(&literal_tree, b'a' as usize);
+		w.write_code(&literal_tree, 259); // length 5
+		w.write_code(&distance_tree, 0); // distance 1
+		w.write_code(&literal_tree, 256);
+		assert_eq!(inflate_all(&w.bytes).unwrap(), b"aaaaaa");
+	}
+
+	#[test]
+	fn truncated() {
+		for stream in [compress(b"", 9), compress(b"abcabcabc", 9), compress(&text(5000), 9), compress(&text(5000), 0)] {
+			for len in 0..stream.len() {
+				assert!(matches!(inflate_all(&stream[..len]), Err(GuyzipError::UnexpectedEof)), "prefix of {} bytes", len);
+			}
+		}
+	}
+
+	#[test]
+	fn over_subscribed_code() {
+		// a dynamic block whose code length code has four codes of length 1
+		let mut w = BitWriter::new();
+		w.write_bits(1, 1);
+		w.write_bits(2, 2); // dynamic codes
+		w.write_bits(0, 5); // HLIT
+		w.write_bits(0, 5); // HDIST
+		w.write_bits(0, 4); // HCLEN, 4 code length codes
+		for _ in 0..4 {
+			w.write_bits(1, 3);
+		}
+		w.write_bits(0, 32);
+		match inflate_all(&w.bytes) {
+			Err(GuyzipError::InvalidData(msg)) => assert!(msg.contains("over-subscribed"), "{}", msg),
+			res => panic!("{:?}", res.map(|_| ())),
+		}
+	}
+
+	#[test]
+	fn distance_too_far_back() {
+		// "a" then a repetition 2 back
+		let literal_tree = huffman::calc_codes(&huffman::LITERAL_FIXED_CODES);
+		let distance_tree = huffman::calc_codes(&huffman::DISTANCE_FIXED_CODES);
+		let mut w = BitWriter::new();
+		w.write_bits(1, 1);
+		w.write_bits(1, 2);
+		w.write_code(&literal_tree, b'a' as usize);
+		w.write_code(&literal_tree, 257); // length 3
+		w.write_code(&distance_tree, 1); // distance 2
+		w.write_code(&literal_tree, 256);
+		match inflate_all(&w.bytes) {
+			Err(GuyzipError::InvalidData(msg)) => assert!(msg.contains("too far back"), "{}", msg),
+			res => panic!("{:?}", res.map(|_| ())),
+		}
+	}
+
+	#[test]
+	fn corrupted_input_does_not_panic() {
+		// bytes of valid streams changed at random decode to something or fail, never panic
+		let streams = [compress(&text(3000), 9), compress(&text(3000), 1), compress(b"abcabcabc", 9), compress(&text(300), 0)];
+		let mut x: u64 = 0x2545F4914F6CDD1D;
+		let mut next = || {
+			x ^= x << 13;
+			x ^= x >> 7;
+			x ^= x << 17;
+			x
+		};
+		for stream in &streams {
+			for _ in 0..2000 {
+				let mut corrupted = stream.clone();
+				for _ in 0..1 + next() % 4 {
+					let i = next() as usize % corrupted.len();
+					corrupted[i] ^= 1 << (next() % 8);
+				}
+				let _ = inflate_all(&corrupted);
+			}
+		}
+	}
+}
@@ -0,0 +1,95 @@
+// guyzip as a library: gzip, zlib and raw deflate compression and decompression.
+
+use std::io::Write;
+
+pub mod adler32;
+pub mod crc32;
+pub mod gzip;
+pub mod zlib;
+mod error;
+mod huffman;
+mod deflate;
+mod inflate;
+mod options;
+
+pub use deflate::{Token, DeflateEncoder};
+pub use error::GuyzipError;
+pub use gzip::{GzEncoder, GzHeader};
+pub use zlib::ZlibEncoder;
+pub use options::{CompressionOptions, Level, MatchFinder};
+
+pub fn gzip_compress(data: &[u8]) -> Result<Vec<u8>, GuyzipError> {
+	gzip_compress_with_options(data, CompressionOptions::default())
+}
+
+pub fn gzip_compress_with_options(data: &[u8], options: CompressionOptions) -> Result<Vec<u8>, GuyzipError> {
+	// a complete gzip file (single member) holding data.
+	let mut encoder = GzEncoder::with_options(vec![], options)?;
+	encoder.write_all(data)?;
+	encoder.finish()
+}
+
+pub fn gzip_decompress(data: &[u8]) -> Result<Vec<u8>, GuyzipError> {
+	gzip::decompress(data)
+}
+
+pub fn zlib_compress(data: &[u8]) -> Result<Vec<u8>, GuyzipError> {
+	zlib_compress_with_options(data, CompressionOptions::default())
+}
+
+pub fn zlib_compress_with_options(data: &[u8], options: CompressionOptions) -> Result<Vec<u8>, GuyzipError> {
+	// a complete zlib stream holding data.
+	let mut encoder = ZlibEncoder::with_options(vec![], options)?;
+	encoder.write_all(data)?;
+	encoder.finish()
+}
+
+pub fn zlib_decompress(data: &[u8]) -> Result<Vec<u8>, GuyzipError> {
+	zlib::decompress(data)
+}
+
+pub fn deflate_raw(data: &[u8]) -> Result<Vec<u8>, GuyzipError> {
+	deflate_raw_with_options(data, CompressionOptions::default())
+}
+
+pub fn deflate_raw_with_options(data: &[u8], options: CompressionOptions) -> Result<Vec<u8>, GuyzipError> {
+	// a bare deflate stream, without any container.
+	let mut encoder = DeflateEncoder::with_options(vec![], options);
+	encoder.write_all(data)?;
+	encoder.finish()
+}
+
+pub fn inflate_raw(data: &[u8]) -> Result<Vec<u8>, GuyzipError> {
+	// decodes a bare deflate stream. anything after the end of the stream is ignored.
+	let mut out = vec![];
+	inflate::inflate(data, &mut out)?;
+	Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn tiny_inputs() {
+		// the match finders look a few bytes ahead, inputs shorter than that must work too
+		for data in [&b""[..], b"a", b"ab", b"aa", b"abc"] {
+			for level in 0..=9 {
+				for match_finder in [MatchFinder::BinaryTree, MatchFinder::HashChain] {
+					let options = CompressionOptions::default().level(Level::new(level).unwrap()).match_finder(match_finder);
+					assert_eq!(gzip_decompress(&gzip_compress_with_options(data, options).unwrap()).unwrap(), data);
+					assert_eq!(zlib_decompress(&zlib_compress_with_options(data, options).unwrap()).unwrap(), data);
+					assert_eq!(inflate_raw(&deflate_raw_with_options(data, options).unwrap()).unwrap(), data);
+
+					// written a byte at a time and flushed in between, so every chunk is tiny
+					let mut encoder = GzEncoder::with_options(vec![], options).unwrap();
+					for b in data {
+						encoder.write_all(&[*b]).unwrap();
+						encoder.flush().unwrap();
+					}
+					assert_eq!(gzip_decompress(&encoder.finish().unwrap()).unwrap(), data);
+				}
+			}
+		}
+	}
+}
@@ -1,41 +1,459 @@
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::fs::{self, File};
-use std::io::{Write, BufWriter};
+use std::io::{self, Read, Write, BufWriter, IsTerminal};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-mod crc32;
-mod huffman;
-mod deflate;
+use guyzip::{GzEncoder, GzHeader, ZlibEncoder, DeflateEncoder, CompressionOptions, Level, MatchFinder, GuyzipError};
 
-fn main() -> std::io::Result<()> {
-	let args: Vec<String> = std::env::args().collect();
+// exit codes, as gzip's
+const EXIT_ERROR: i32 = 1;
+const EXIT_WARNING: i32 = 2;
 
-	let in_path = Path::new(args.get(1).expect("must supply a file to compress"));
-	let out_file_name = format!("{}.gz", in_path.file_name().unwrap().to_str().unwrap());
-	let out_path = Path::new(&out_file_name);
+const USAGE: &str = "usage: guyzip [-cdfhkNnqrv] [-1..-9] [-S suffix] [-p threads] [--format gzip|zlib|raw] [--iterations n]\n\
+	\t[--match-finder binary-tree|hash-chain] [--search-depth n] [--nice-len n] [file ...]";
 
-	let mut file: Vec<u8> = fs::read(in_path)?;
-	file.reserve(1024); // make sure reading a little after the end won't segfault.
-	compress(&file, out_path)?;
-	Ok(())
+#[derive(Clone, Copy)]
+enum Format {
+	Gzip,
+	Zlib,
+	Raw, // a bare deflate stream
+}
+
+impl Format {
+	fn suffix(self) -> &'static str {
+		match self {
+			Format::Gzip => ".gz",
+			Format::Zlib => ".zz",
+			Format::Raw => ".deflate",
+		}
+	}
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Verbosity {
+	Quiet, // no warnings
+	Normal,
+	Verbose, // a line for every file
+}
+
+struct Settings {
+	decompress: bool,
+	to_stdout: bool,
+	keep: bool, // don't remove the input files
+	force: bool, // overwrite outputs, write compressed data to a terminal
+	recursive: bool,
+	verbosity: Verbosity,
+	name: Option<bool>, // -N / -n. by default the name and time are saved when compressing but not restored when decompressing
+	suffix: Option<String>, // -S, the format's suffix by default
+	format: Format,
+	options: CompressionOptions,
+}
+
+impl Settings {
+	fn suffix(&self) -> &str {
+		self.suffix.as_deref().unwrap_or_else(|| self.format.suffix())
+	}
+}
+
+fn main() {
+	let (settings, files) = parse_args();
+	let mut run = Run { settings, exit_code: 0 };
+	if files.is_empty() {
+		run.process_stdin();
+	}
+	for file in &files {
+		if file == "-" {
+			run.process_stdin();
+		} else {
+			run.process_path(Path::new(file));
+		}
+	}
+	std::process::exit(run.exit_code);
+}
+
+fn parse_args() -> (Settings, Vec<String>) {
+	let mut settings = Settings {
+		decompress: false,
+		to_stdout: false,
+		keep: false,
+		force: false,
+		recursive: false,
+		verbosity: Verbosity::Normal,
+		name: None,
+		suffix: None,
+		format: Format::Gzip,
+		options: CompressionOptions::default()
+			.threads(std::thread::available_parallelism().map_or(1, |n| n.get())),
+	};
+	let mut files = vec![];
+	let mut args = std::env::args().skip(1);
+	while let Some(arg) = args.next() {
+		if arg == "--" {
+			files.extend(args.by_ref());
+			break;
+		}
+		if let Some(long) = arg.strip_prefix("--") {
+			// --option value or --option=value
+			let (option, value) = match long.split_once('=') {
+				Some((option, value)) => (option, Some(value.to_string())),
+				None => (long, None),
+			};
+			let mut value = value.into_iter().chain(args.by_ref());
+			apply_option(&mut settings, option, &mut value);
+		} else if arg.len() > 1 && arg.starts_with('-') {
+			// short options can be grouped, as in -dkv. one taking a value takes the rest of the group or the next argument
+			for (i, c) in arg.char_indices().skip(1) {
+				if c == 'S' || c == 'p' {
+					let rest = Some(arg[i + 1..].to_string()).filter(|rest| !rest.is_empty());
+					apply_option(&mut settings, &c.to_string(), &mut rest.into_iter().chain(args.by_ref()));
+					break;
+				}
+				apply_option(&mut settings, &c.to_string(), &mut std::iter::empty());
+			}
+		} else {
+			files.push(arg);
+		}
+	}
+	(settings, files)
+}
+
+fn apply_option(settings: &mut Settings, option: &str, values: &mut dyn Iterator<Item = String>) {
+	// option is a short option letter or a long option name, without the dashes
+	let mut value = |what: &str| match values.next() {
+		Some(value) => value,
+		None => usage_error(&format!("option {} needs {}", option, what)),
+	};
+	match option {
+		"d" | "decompress" | "uncompress" => settings.decompress = true,
+		"c" | "stdout" | "to-stdout" => settings.to_stdout = true,
+		"k" | "keep" => settings.keep = true,
+		"f" | "force" => settings.force = true,
+		"r" | "recursive" => settings.recursive = true,
+		"v" | "verbose" => settings.verbosity = Verbosity::Verbose,
+		"q" | "quiet" => settings.verbosity = Verbosity::Quiet,
+		"N" | "name" => settings.name = Some(true),
+		"n" | "no-name" => settings.name = Some(false),
+		"S" | "suffix" => {
+			let suffix = value("a suffix");
+			if suffix.is_empty() || suffix.contains('/') {
+				usage_error(&format!("invalid suffix '{}'", suffix));
+			}
+			settings.suffix = Some(suffix);
+		}
+		"1" | "2" | "3" | "4" | "5" | "6" | "7" | "8" | "9" => {
+			settings.options.level = option.parse().ok().and_then(Level::new)
+				.unwrap_or_else(|| usage_error(&format!("invalid level {}", option)));
+		}
+		"fast" => settings.options.level = Level::FAST,
+		"best" => settings.options.level = Level::BEST,
+		"iterations" => {
+			settings.options.iterations = value("a number").parse()
+				.unwrap_or_else(|_| usage_error("option iterations needs a number"));
+		}
+		"p" | "processes" => {
+			settings.options.threads = match value("a number").parse() {
+				Ok(n) if n > 0 => n,
+				_ => usage_error(&format!("option {} needs a positive number", option)),
+			};
+		}
+		"match-finder" => {
+			settings.options.match_finder = match value("a match finder").as_str() {
+				"binary-tree" => MatchFinder::BinaryTree,
+				"hash-chain" => MatchFinder::HashChain,
+				_ => usage_error("option match-finder needs binary-tree or hash-chain"),
+			};
+		}
+		"search-depth" | "nice-len" => {
+			// 0 means no limit, as in CompressionOptions
+			let n = value("a number").parse().unwrap_or_else(|_| usage_error(&format!("option {} needs a number", option)));
+			if option == "search-depth" {
+				settings.options.search_depth = n;
+			} else {
+				settings.options.nice_len = n;
+			}
+		}
+		"format" => {
+			settings.format = match value("a format").as_str() {
+				"gzip" => Format::Gzip,
+				"zlib" => Format::Zlib,
+				"raw" => Format::Raw,
+				_ => usage_error("option format needs gzip, zlib or raw"),
+			};
+		}
+		"h" | "help" => {
+			println!("{}", USAGE);
+			std::process::exit(0);
+		}
+		_ => usage_error(&format!("unknown option {}", option)),
+	}
 }
 
-fn compress(file: &[u8], out_path: &Path) -> std::io::Result<()> {
-	let mut out_file = BufWriter::with_capacity(1 << 20, File::create(out_path)?);
-	
-	// gzip header
-	out_file.write_all(&[0x1F, 0x8B])?; // magic
-	out_file.write_all(&[0x08])?; // Compression Method = DEFLATE
-	out_file.write_all(&[0x00])?; // Flags - none
-	out_file.write_all(&[0x00, 0x00, 0x00, 0x00])?; // Modification Time - none
-	out_file.write_all(&[0x00])?; // Extra Flags - None
-	out_file.write_all(&[0xFF])?; // OS - unknown
+fn usage_error(msg: &str) -> ! {
+	eprintln!("guyzip: {}", msg);
+	eprintln!("{}", USAGE);
+	std::process::exit(EXIT_ERROR);
+}
+
+struct Run {
+	settings: Settings,
+	exit_code: i32, // the worst of what happened so far
+}
+
+impl Run {
+	fn error(&mut self, msg: &str) {
+		eprintln!("guyzip: {}", msg);
+		self.exit_code = EXIT_ERROR;
+	}
+
+	fn warning(&mut self, msg: &str) {
+		if self.settings.verbosity > Verbosity::Quiet {
+			eprintln!("guyzip: {}", msg);
+		}
+		if self.exit_code == 0 {
+			self.exit_code = EXIT_WARNING;
+		}
+	}
 
-	deflate::deflate(file, &mut out_file);
+	fn process_stdin(&mut self) {
+		let settings = &self.settings;
+		let res = if settings.decompress {
+			if io::stdin().is_terminal() && !settings.force {
+				return self.error("compressed data not read from a terminal. Use -f to force decompression.");
+			}
+			decompress_stdin(settings.format)
+		} else {
+			if io::stdout().is_terminal() && !settings.force {
+				return self.error("compressed data not written to a terminal. Use -f to force compression.");
+			}
+			compress(&mut io::stdin().lock(), io::stdout().lock(), settings, &GzHeader::new())
+				.and_then(|mut out| Ok(out.flush()?))
+		};
+		if let Err(e) = res {
+			self.error(&format!("stdin: {}", e));
+		}
+	}
 
-	let crc32 = crc32::crc32(file);
-	out_file.write_all(&crc32.to_le_bytes())?; // CRC32
-	let size: u32 = file.len() as u32 & 0xFFFFFFFF;
-	out_file.write_all(&size.to_le_bytes())?; // size modulo 2^32
+	fn process_path(&mut self, path: &Path) {
+		let metadata = match fs::symlink_metadata(path) {
+			Ok(metadata) => metadata,
+			Err(e) => return self.error(&format!("{}: {}", path.display(), e)),
+		};
+		if metadata.is_dir() {
+			if !self.settings.recursive {
+				return self.warning(&format!("{} is a directory -- ignored", path.display()));
+			}
+			let entries = fs::read_dir(path).and_then(|entries| entries.map(|entry| entry.map(|e| e.path())).collect());
+			let mut entries: Vec<PathBuf> = match entries {
+				Ok(entries) => entries,
+				Err(e) => return self.error(&format!("{}: {}", path.display(), e)),
+			};
+			entries.sort();
+			for entry in entries {
+				self.process_path(&entry);
+			}
+			return;
+		}
+		// like gzip, symbolic links are only followed with -f
+		let is_file = metadata.is_file() || (metadata.file_type().is_symlink() && self.settings.force);
+		if !is_file {
+			return self.warning(&format!("{} is not a directory or a regular file -- ignored", path.display()));
+		}
 
+		if self.settings.decompress {
+			self.decompress_file(path);
+		} else {
+			self.compress_file(path);
+		}
+	}
+
+	fn compress_file(&mut self, in_path: &Path) {
+		let settings = &self.settings;
+		let in_file_name = in_path.file_name().unwrap_or_default().to_string_lossy().into_owned();
+		if in_file_name.ends_with(settings.suffix()) && !settings.to_stdout {
+			// not an error for gzip, and silent when recursing
+			if settings.verbosity > Verbosity::Quiet && !settings.recursive {
+				eprintln!("guyzip: {} already has {} suffix -- unchanged", in_path.display(), settings.suffix());
+			}
+			return;
+		}
+		if settings.to_stdout && io::stdout().is_terminal() && !settings.force {
+			return self.error("compressed data not written to a terminal. Use -f to force compression.");
+		}
+		let mut out_path = in_path.as_os_str().to_owned();
+		out_path.push(settings.suffix());
+		let out_path = PathBuf::from(out_path);
+
+		let res = (|| {
+			let mut in_file = File::open(in_path)?;
+			let in_metadata = in_file.metadata()?;
+			// like gzip, remember the name and modification time of the original file, unless -n
+			let mut header = GzHeader::new();
+			if settings.name != Some(false) {
+				header = header.file_name(&in_file_name);
+				if let Ok(modified) = in_metadata.modified() {
+					header = header.modified(modified);
+				}
+			}
+			if cfg!(unix) {
+				header = header.os(3);
+			}
+
+			if settings.to_stdout {
+				compress(&mut in_file, io::stdout().lock(), settings, &header)?.flush()?;
+				return Ok(None);
+			}
+			let out_file = create_output(&out_path, settings.force)?;
+			let compressed_len = compress(&mut in_file, out_file, settings, &header)
+				.and_then(|out_file| {
+					copy_metadata(&out_file, &in_metadata, in_metadata.modified().ok())?;
+					Ok(out_file.metadata()?.len())
+				})
+				.inspect_err(|_| {
+					let _ = fs::remove_file(&out_path);
+				})?;
+			Ok(Some((compressed_len, in_metadata.len())))
+		})();
+		self.finish_file(in_path, &out_path, res);
+	}
+
+	fn decompress_file(&mut self, in_path: &Path) {
+		let settings = &self.settings;
+		let in_file_name = in_path.file_name().unwrap_or_default().to_string_lossy().into_owned();
+		let out_file_name = match in_file_name.strip_suffix(settings.suffix()) {
+			Some(name) if !name.is_empty() => Some(name.to_string()),
+			_ => match (settings.format, in_file_name.strip_suffix(".tgz")) {
+				(Format::Gzip, Some(name)) if settings.suffix.is_none() && !name.is_empty() => Some(format!("{}.tar", name)),
+				_ => None,
+			},
+		};
+		let out_file_name = match out_file_name {
+			Some(name) => name,
+			None if settings.to_stdout => String::new(), // the name doesn't matter
+			None if settings.recursive => return, // other files are expected when recursing
+			None => return self.warning(&format!("{}: unknown suffix -- ignored", in_path.display())),
+		};
+
+		let mut out_path = in_path.with_file_name(&out_file_name);
+		let res = (|| {
+			let compressed = fs::read(in_path)?;
+			let decompressed = decompress(&compressed, settings.format)?;
+			if settings.to_stdout {
+				write_stdout(&decompressed)?;
+				return Ok(None);
+			}
+			let in_metadata = fs::metadata(in_path)?;
+			let mut modified = in_metadata.modified().ok();
+			if let (Format::Gzip, Some(true)) = (settings.format, settings.name) {
+				// restore the original name and time, if stored
+				let header = guyzip::gzip::read_header(&compressed)?;
+				if let Some(name) = header.get_file_name() {
+					let name: String = name.iter().map(|b| *b as char).collect(); // latin-1
+					// only the last component, so a stored name can't write elsewhere
+					if let Some(name) = Path::new(&name).file_name() {
+						out_path = in_path.with_file_name(name);
+					}
+				}
+				if header.get_mtime() != 0 {
+					modified = Some(UNIX_EPOCH + Duration::from_secs(header.get_mtime() as u64));
+				}
+			}
+
+			let mut out_file = create_output(&out_path, settings.force)?;
+			out_file.write_all(&decompressed)
+				.and_then(|_| copy_metadata(&out_file, &in_metadata, modified))
+				.inspect_err(|_| {
+					let _ = fs::remove_file(&out_path);
+				})?;
+			Ok(Some((compressed.len() as u64, decompressed.len() as u64)))
+		})();
+		self.finish_file(in_path, &out_path, res);
+	}
+
+	fn finish_file(&mut self, in_path: &Path, out_path: &Path, res: Result<Option<(u64, u64)>, GuyzipError>) {
+		// res is Some((compressed size, uncompressed size)) if an output file was written, after which the input is removed.
+		let (compressed_len, uncompressed_len) = match res {
+			Ok(Some(sizes)) => sizes,
+			Ok(None) => return,
+			Err(GuyzipError::Io(e)) if e.kind() == io::ErrorKind::AlreadyExists => {
+				return self.warning(&format!("{} already exists;\tnot overwritten", out_path.display()));
+			}
+			Err(e) => return self.error(&format!("{}: {}", in_path.display(), e)),
+		};
+		if !self.settings.keep {
+			if let Err(e) = fs::remove_file(in_path) {
+				return self.error(&format!("{}: {}", in_path.display(), e));
+			}
+		}
+		if self.settings.verbosity == Verbosity::Verbose {
+			// like gzip, how much smaller the compressed file is than the uncompressed one
+			let ratio = if uncompressed_len == 0 {0.0} else {100.0 * (1.0 - compressed_len as f64 / uncompressed_len as f64)};
+			let action = if self.settings.keep {"created"} else {"replaced with"};
+			eprintln!("{}:\t{:5.1}% -- {} {}", in_path.display(), ratio, action, out_path.display());
+		}
+	}
+}
+
+fn create_output(out_path: &Path, force: bool) -> io::Result<File> {
+	// fails with AlreadyExists if the file exists, unless force
+	if force {
+		File::create(out_path)
+	} else {
+		File::options().write(true).create_new(true).open(out_path)
+	}
+}
+
+fn copy_metadata(out_file: &File, in_metadata: &fs::Metadata, modified: Option<SystemTime>) -> io::Result<()> {
+	// like gzip, the output gets the permissions and modification time of the input
+	out_file.set_permissions(in_metadata.permissions())?;
+	if let Some(modified) = modified {
+		out_file.set_modified(modified)?;
+	}
 	Ok(())
 }
+
+fn decompress_stdin(format: Format) -> Result<(), GuyzipError> {
+	let mut compressed = vec![];
+	io::stdin().lock().read_to_end(&mut compressed)?;
+	let decompressed = decompress(&compressed, format)?;
+	write_stdout(&decompressed)?;
+	Ok(())
+}
+
+fn write_stdout(data: &[u8]) -> io::Result<()> {
+	// flushed here, so a failing write is reported instead of lost when stdout is dropped
+	let mut stdout = io::stdout().lock();
+	stdout.write_all(data)?;
+	stdout.flush()
+}
+
+fn compress<W: Write>(input: &mut impl Read, out: W, settings: &Settings, header: &GzHeader) -> Result<W, GuyzipError> {
+	// header is only used by gzip
+	let out = BufWriter::with_capacity(1 << 20, out);
+	let options = settings.options;
+	let out = match settings.format {
+		Format::Gzip => {
+			let mut encoder = GzEncoder::with_header(out, options, header)?;
+			io::copy(input, &mut encoder)?;
+			encoder.finish()?
+		}
+		Format::Zlib => {
+			let mut encoder = ZlibEncoder::with_options(out, options)?;
+			io::copy(input, &mut encoder)?;
+			encoder.finish()?
+		}
+		Format::Raw => {
+			let mut encoder = DeflateEncoder::with_options(out, options);
+			io::copy(input, &mut encoder)?;
+			encoder.finish()?
+		}
+	};
+	Ok(out.into_inner().map_err(|e| e.into_error())?)
+}
+
+fn decompress(compressed: &[u8], format: Format) -> Result<Vec<u8>, GuyzipError> {
+	match format {
+		Format::Gzip => guyzip::gzip_decompress(compressed),
+		Format::Zlib => guyzip::zlib_decompress(compressed),
+		Format::Raw => guyzip::inflate_raw(compressed),
+	}
+}
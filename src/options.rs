@@ -0,0 +1,134 @@
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Level(u8);
+
+impl Level {
+	pub const STORE: Level = Level(0);
+	pub const FAST: Level = Level(1);
+	pub const BEST: Level = Level(9);
+
+	pub fn new(level: u8) -> Option<Level> {
+		// levels go from 0 (no compression) to 9 (smallest output)
+		if level <= 9 {
+			Some(Level(level))
+		} else {
+			None
+		}
+	}
+
+	pub fn get(self) -> u8 {
+		self.0
+	}
+
+	pub(crate) fn strategy(self) -> Strategy {
+		// the greedy and lazy parameters are the ones zlib uses for the same levels.
+		match self.0 {
+			0 => Strategy::Store,
+			1 => Strategy::Greedy(MatchParams { max_chain: 4, nice_len: 8, good_len: 4, max_lazy: 4 }),
+			2 => Strategy::Greedy(MatchParams { max_chain: 8, nice_len: 16, good_len: 4, max_lazy: 5 }),
+			3 => Strategy::Greedy(MatchParams { max_chain: 32, nice_len: 32, good_len: 4, max_lazy: 6 }),
+			4 => Strategy::Lazy(MatchParams { max_chain: 16, nice_len: 16, good_len: 4, max_lazy: 4 }),
+			5 => Strategy::Lazy(MatchParams { max_chain: 32, nice_len: 32, good_len: 8, max_lazy: 16 }),
+			6 => Strategy::Lazy(MatchParams { max_chain: 128, nice_len: 128, good_len: 8, max_lazy: 16 }),
+			7 => Strategy::Lazy(MatchParams { max_chain: 256, nice_len: 128, good_len: 8, max_lazy: 32 }),
+			8 => Strategy::Lazy(MatchParams { max_chain: 1024, nice_len: 258, good_len: 32, max_lazy: 128 }),
+			_ => Strategy::Optimal,
+		}
+	}
+}
+
+impl Default for Level {
+	fn default() -> Level {
+		Level::BEST
+	}
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+#[non_exhaustive]
+pub struct CompressionOptions {
+	// built like CompressionOptions::default().level(Level::FAST).threads(4), so adding options doesn't break callers
+	pub level: Level,
+	pub iterations: u32, // extra passes of the optimal parser (level 9), each using the huffman codes the previous one ended with
+	pub threads: usize, // how many chunks to compress in parallel, 0 and 1 both mean on the calling thread. doesn't change the output.
+	pub match_finder: MatchFinder, // how the optimal parser (level 9) finds repetitions
+	pub search_depth: usize, // how many earlier positions level 9 tries per byte. 0 means all of them for the binary tree and 256 for the hash chain
+	pub nice_len: usize, // level 9 stops searching at a repetition this long, 0 means 258 (the longest)
+}
+
+impl CompressionOptions {
+	pub fn level(mut self, level: Level) -> CompressionOptions {
+		self.level = level;
+		self
+	}
+
+	pub fn iterations(mut self, iterations: u32) -> CompressionOptions {
+		self.iterations = iterations;
+		self
+	}
+
+	pub fn threads(mut self, threads: usize) -> CompressionOptions {
+		self.threads = threads;
+		self
+	}
+
+	pub fn match_finder(mut self, match_finder: MatchFinder) -> CompressionOptions {
+		self.match_finder = match_finder;
+		self
+	}
+
+	pub fn search_depth(mut self, search_depth: usize) -> CompressionOptions {
+		self.search_depth = search_depth;
+		self
+	}
+
+	pub fn nice_len(mut self, nice_len: usize) -> CompressionOptions {
+		self.nice_len = nice_len;
+		self
+	}
+
+	pub(crate) fn finder_params(&self) -> FinderParams {
+		FinderParams {
+			finder: self.match_finder,
+			max_depth: match (self.search_depth, self.match_finder) {
+				(0, MatchFinder::BinaryTree) => usize::MAX,
+				(0, MatchFinder::HashChain) => HASH_CHAIN_DEPTH,
+				(depth, _) => depth,
+			},
+			nice_len: if self.nice_len == 0 {usize::MAX} else {self.nice_len},
+		}
+	}
+}
+
+// the hash chain's default search depth. the parse tries every position it reaches, so even zlib's 4096 takes
+// over a minute per MB of very repetitive data, this takes a few seconds and costs text about 0.5% in size.
+const HASH_CHAIN_DEPTH: usize = 256;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MatchFinder {
+	HashChain, // earlier positions with the same hash, closest first
+	#[default]
+	BinaryTree, // a search tree per hash, fast enough to search every earlier position
+}
+
+#[derive(Clone, Copy)]
+pub(crate) enum Strategy {
+	Store, // stored blocks only
+	Greedy(MatchParams), // always take the longest match found
+	Lazy(MatchParams), // take a match only if the next position doesn't start a longer one
+	Optimal, // shortest path over all matches, see lempel_ziv
+}
+
+#[derive(Clone, Copy)]
+pub(crate) struct FinderParams {
+	// what the optimal parser searches with. with no depth or nice_len limits both finders give the same output.
+	pub finder: MatchFinder,
+	pub max_depth: usize,
+	pub nice_len: usize,
+}
+
+#[derive(Clone, Copy)]
+pub(crate) struct MatchParams {
+	pub max_chain: usize, // how many earlier positions to try per match search
+	pub nice_len: usize, // stop searching once a match this long is found
+	pub good_len: usize, // search less when the pending match is already this long (lazy only)
+	pub max_lazy: usize, // don't look for a better match when the pending one is this long. for greedy, longer matches aren't indexed.
+}
@@ -0,0 +1,91 @@
+use std::io::{self, Write};
+
+use crate::adler32;
+use crate::deflate::DeflateEncoder;
+use crate::error::GuyzipError;
+use crate::options::CompressionOptions;
+use crate::inflate::{self, invalid_data};
+
+pub struct ZlibEncoder<W: Write> {
+	// writes a zlib stream, compressing data as it is written.
+	deflate_encoder: DeflateEncoder<W>,
+	adler32: u32,
+}
+
+impl<W: Write> ZlibEncoder<W> {
+	pub fn new(out: W) -> Result<ZlibEncoder<W>, GuyzipError> {
+		ZlibEncoder::with_options(out, CompressionOptions::default())
+	}
+
+	pub fn with_options(mut out: W, options: CompressionOptions) -> Result<ZlibEncoder<W>, GuyzipError> {
+		// zlib header
+		let cmf: u16 = 0x78; // Compression Method = DEFLATE, Compression Info = 32K window
+		// the compression level, informative only. the same grouping zlib uses
+		let flevel = match options.level.get() {
+			0 | 1 => 0, // fastest
+			2..=5 => 1, // fast
+			6 => 2, // default
+			_ => 3, // maximum compression
+		};
+		let mut header = (cmf << 8) | (flevel << 6); // no preset dictionary
+		header += (31 - header % 31) % 31; // FCHECK makes the header, as a big endian u16, a multiple of 31
+		out.write_all(&header.to_be_bytes())?;
+
+		Ok(ZlibEncoder {
+			deflate_encoder: DeflateEncoder::with_options(out, options),
+			adler32: 1,
+		})
+	}
+
+	pub fn finish(self) -> Result<W, GuyzipError> {
+		let mut out = self.deflate_encoder.finish()?;
+		out.write_all(&self.adler32.to_be_bytes())?; // ADLER32 of the uncompressed data
+		Ok(out)
+	}
+}
+
+impl<W: Write> Write for ZlibEncoder<W> {
+	fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+		let taken = self.deflate_encoder.write(data)?;
+		self.adler32 = adler32::update(self.adler32, &data[..taken]);
+		Ok(taken)
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		self.deflate_encoder.flush()
+	}
+}
+
+pub fn decompress(data: &[u8]) -> Result<Vec<u8>, GuyzipError> {
+	// decodes a single zlib stream. anything after it is ignored.
+	if data.len() < 2 {
+		return Err(GuyzipError::UnexpectedEof);
+	}
+	let (cmf, flg) = (data[0], data[1]);
+	if cmf & 0x0F != 8 {
+		return Err(invalid_data(&format!("unknown compression method {}", cmf & 0x0F)));
+	}
+	if cmf >> 4 > 7 {
+		return Err(invalid_data("invalid window size"));
+	}
+	if u16::from_be_bytes([cmf, flg]) % 31 != 0 {
+		return Err(invalid_data("incorrect header check"));
+	}
+	if flg & (1 << 5) != 0 {
+		return Err(invalid_data("preset dictionaries are not supported"));
+	}
+	let rest = &data[2..];
+
+	let mut out = vec![];
+	let deflate_len = inflate::inflate(rest, &mut out)?;
+	let rest = &rest[deflate_len..];
+	if rest.len() < 4 {
+		return Err(GuyzipError::UnexpectedEof);
+	}
+	let expected_adler32 = u32::from_be_bytes([rest[0], rest[1], rest[2], rest[3]]);
+	let adler32 = adler32::adler32(&out);
+	if adler32 != expected_adler32 {
+		return Err(invalid_data(&format!("adler32 mismatch: stored {:08x}, computed {:08x}", expected_adler32, adler32)));
+	}
+	Ok(out)
+}